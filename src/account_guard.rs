@@ -0,0 +1,237 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use anyhow::{anyhow, Context};
+use fixed::types::I80F48;
+use log::{debug, info, warn};
+use mango_v4::health::HealthType;
+use mango_v4::state::{PerpMarket, QUOTE_DECIMALS, QUOTE_TOKEN_INDEX};
+use tokio::sync::{Semaphore, SemaphorePermit};
+use tokio::time::timeout;
+use crate::{CacheControl, MangoClientRef};
+use crate::circuit_breaker::CircuitBreaker;
+use crate::metrics::Metrics;
+use crate::services::trading_config::{BotConfig, TradingConfig};
+use crate::trade_sequence::Direction;
+
+// account-wide checks shared by all pairs trading on the same mango account
+pub struct AccountGuard {
+    bot_config: Arc<BotConfig>,
+    // limit trade sequences running in parallel across all pairs
+    trade_slots: Semaphore,
+    metrics: Arc<Metrics>,
+    circuit_breaker: Arc<CircuitBreaker>,
+    // no new trades or rebalances once set
+    shutting_down: AtomicBool,
+}
+
+impl AccountGuard {
+
+    pub fn new(bot_config: Arc<BotConfig>, metrics: Arc<Metrics>, circuit_breaker: Arc<CircuitBreaker>) -> Self {
+        let trade_slots = Semaphore::new(bot_config.max_concurrent_trades);
+        AccountGuard {
+            bot_config,
+            trade_slots,
+            metrics,
+            circuit_breaker,
+            shutting_down: AtomicBool::new(false),
+        }
+    }
+
+    // returns a permit which must be held for the duration of the trade sequence
+    // note: invalidates mango account cache
+    pub async fn try_begin_trade(&self, mango_client: Arc<MangoClientRef>, trading_config: &TradingConfig,
+                                 direction: Direction, price: f64) -> Option<SemaphorePermit<'_>> {
+        let pair_name = trading_config.pair_name();
+        if self.shutting_down.load(Ordering::SeqCst) {
+            info!("{}: shutting down, skipping trade", pair_name);
+            self.refuse(&pair_name, "shutdown");
+            return None;
+        }
+        if let Some(reason) = self.circuit_breaker.halt_reason() {
+            info!("{}: trading halted ({}), skipping trade", pair_name, reason);
+            self.refuse(&pair_name, "halted");
+            return None;
+        }
+        let Ok(permit) = self.trade_slots.try_acquire() else {
+            info!("{}: another trade sequence is running on the account, skipping ...", pair_name);
+            return None;
+        };
+
+        let health = project_health(mango_client, trading_config, direction, price).await;
+        self.circuit_breaker.record_rpc_result(health.is_ok());
+        let health = match health {
+            Ok(health) => health,
+            Err(err) => {
+                warn!("{}: failed to compute account health, skipping trade: {}", pair_name, err);
+                self.refuse(&pair_name, "health_unknown");
+                return None;
+            }
+        };
+        self.metrics.set_gauge("account_init_health_ui", &[], health.init_health_ui);
+        self.metrics.set_gauge("account_maint_health_ui", &[], health.maint_health_ui);
+        self.metrics.set_gauge("projected_init_health_ui", &[("pair", &pair_name)], health.projected_init_health_ui);
+
+        let reserved = self.bot_config.collateral_reserved_by_others(&trading_config.perp_market_name);
+        let buffer = self.bot_config.init_health_buffer_ui;
+        debug!("{}: init health {:.2} USDC (maint {:.2}), after {} trade {:.2}, reserved by other pairs {:.2}, buffer {:.2}",
+            pair_name, health.init_health_ui, health.maint_health_ui, direction, health.projected_init_health_ui, reserved, buffer);
+
+        if !leaves_health_buffer(&health, reserved, buffer) {
+            info!("{}: init health after trade too low ({:.2} - {:.2} reserved < {:.2} buffer, now {:.2}), skipping trade",
+                pair_name, health.projected_init_health_ui, reserved, buffer, health.init_health_ui);
+            self.refuse(&pair_name, "init_health");
+            return None;
+        }
+
+        self.circuit_breaker.record_trade_started();
+        Some(permit)
+    }
+
+    // reducing exposure needs no health check but must not interleave with trade sequences on the account
+    // and stops with the circuit breaker; takes all trade slots so no trade sequence runs during the rebalance
+    pub fn try_begin_rebalance(&self) -> Option<SemaphorePermit<'_>> {
+        if self.shutting_down.load(Ordering::SeqCst) || !self.circuit_breaker.is_trading_allowed() {
+            return None;
+        }
+        self.trade_slots.try_acquire_many(self.bot_config.max_concurrent_trades as u32).ok()
+    }
+
+    // refuses all further trades and waits until the running trade sequences finished;
+    // false if some are still running after max_wait
+    pub async fn shutdown(&self, max_wait: Duration) -> bool {
+        self.shutting_down.store(true, Ordering::SeqCst);
+        let all_slots = self.bot_config.max_concurrent_trades as u32;
+        match timeout(max_wait, self.trade_slots.acquire_many(all_slots)).await {
+            Ok(Ok(permits)) => {
+                // keep the slots taken
+                permits.forget();
+                true
+            }
+            Ok(Err(_closed)) => true,
+            Err(_elapsed) => false,
+        }
+    }
+
+    fn refuse(&self, pair_name: &str, reason: &str) {
+        self.metrics.inc_counter("trades_refused_total", &[("pair", pair_name), ("reason", reason)]);
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+struct HealthProjection {
+    init_health_ui: f64,
+    maint_health_ui: f64,
+    // init health once both legs are filled
+    projected_init_health_ui: f64,
+}
+
+async fn project_health(mango_client: Arc<MangoClientRef>, trading_config: &TradingConfig,
+                        direction: Direction, price: f64) -> anyhow::Result<HealthProjection> {
+    mango_client.clear_account_cache();
+    let mango_account = mango_client.mango_account().await?;
+    let mut health_cache = mango_client.health_cache(&mango_account).await?;
+    let init_health = health_cache.health(HealthType::Init);
+    let maint_health = health_cache.health(HealthType::Maint);
+
+    let token_index = *mango_client.context.token_indexes_by_name.get(&trading_config.token_name)
+        .with_context(|| format!("token <{}> not found in mango group", trading_config.token_name))?;
+    let market_index = *mango_client.context.perp_market_indexes_by_name.get(&trading_config.perp_market_name)
+        .with_context(|| format!("perp market <{}> not found in mango group", trading_config.perp_market_name))?;
+    let perp_market = mango_client.context.perp_markets.get(&market_index).unwrap().market.clone();
+    let base_bank = mango_client.first_bank(token_index).await?;
+    let quote_bank = mango_client.first_bank(QUOTE_TOKEN_INDEX).await?;
+
+    let base_native = I80F48::from_num(trading_config.base_qty_ui * 10f64.powi(trading_config.base_decimals as i32));
+    let quote_native = I80F48::from_num(trading_config.base_qty_ui * price * 10f64.powi(QUOTE_DECIMALS as i32));
+
+    // swap leg settles in the mango account
+    let (base_change, quote_change) = match direction {
+        Direction::Swap2Perp => (base_native, -quote_native),
+        Direction::Perp2Swap => (-base_native, quote_native),
+    };
+    health_cache.adjust_token_balance(&base_bank, base_change)
+        .map_err(|err| anyhow!("can't project {} balance: {}", trading_config.token_name, err))?;
+    health_cache.adjust_token_balance(&quote_bank, quote_change)
+        .map_err(|err| anyhow!("can't project quote balance: {}", err))?;
+
+    let projected_init_health = project_perp_leg(health_cache.health(HealthType::Init), &perp_market, direction, quote_native);
+
+    let scale = 10f64.powi(QUOTE_DECIMALS as i32);
+    Ok(HealthProjection {
+        init_health_ui: init_health.to_num::<f64>() / scale,
+        maint_health_ui: maint_health.to_num::<f64>() / scale,
+        projected_init_health_ui: projected_init_health.to_num::<f64>() / scale,
+    })
+}
+
+// perp leg: conservatively weight the full notional as a new position (ignores offsetting an existing one)
+fn project_perp_leg(init_health: I80F48, perp_market: &PerpMarket, direction: Direction, quote_native: I80F48) -> I80F48 {
+    let perp_weight = match direction {
+        Direction::Swap2Perp => perp_market.init_base_liab_weight - I80F48::ONE,
+        Direction::Perp2Swap => I80F48::ONE - perp_market.init_base_asset_weight,
+    };
+    init_health - quote_native * perp_weight
+}
+
+// collateral reserved by the other pairs is not available to this trade
+fn leaves_health_buffer(health: &HealthProjection, reserved_ui: f64, buffer_ui: f64) -> bool {
+    health.projected_init_health_ui - reserved_ui >= buffer_ui
+}
+
+#[cfg(test)]
+mod test {
+    use bytemuck::Zeroable;
+    use super::*;
+
+    fn perp_market() -> PerpMarket {
+        let mut perp_market = PerpMarket::zeroed();
+        perp_market.init_base_liab_weight = I80F48::from_num(1.1);
+        perp_market.init_base_asset_weight = I80F48::from_num(0.95);
+        perp_market
+    }
+
+    #[test]
+    fn short_perp_leg_is_weighted_with_liab_weight() {
+        // 1000 USDC init health after the swap, 100 USDC short
+        let projected = project_perp_leg(I80F48::from_num(1_000_000_000), &perp_market(), Direction::Swap2Perp, I80F48::from_num(100_000_000));
+        assert!((projected.to_num::<f64>() - 990_000_000.0).abs() < 1.0, "projected {}", projected);
+    }
+
+    #[test]
+    fn long_perp_leg_is_weighted_with_asset_weight() {
+        let projected = project_perp_leg(I80F48::from_num(1_000_000_000), &perp_market(), Direction::Perp2Swap, I80F48::from_num(100_000_000));
+        assert!((projected.to_num::<f64>() - 995_000_000.0).abs() < 1.0, "projected {}", projected);
+    }
+
+    #[test]
+    fn refuse_below_health_buffer() {
+        let health = HealthProjection {
+            init_health_ui: 20.0,
+            maint_health_ui: 30.0,
+            projected_init_health_ui: 15.0,
+        };
+        assert!(leaves_health_buffer(&health, 5.0, 10.0));
+        assert!(!leaves_health_buffer(&health, 5.5, 10.0));
+        assert!(!leaves_health_buffer(&health, 0.0, 15.5));
+    }
+
+    #[test]
+    fn rebalance_takes_all_trade_slots() {
+        let bot_config: BotConfig = toml::from_str(r#"
+            sol_price_ui_estimate = 20.0
+            max_concurrent_trades = 2
+            pair = []
+        "#).unwrap();
+        let metrics = Arc::new(Metrics::default());
+        let circuit_breaker = Arc::new(CircuitBreaker::new(bot_config.circuit_breaker.clone(), metrics.clone()));
+        let guard = AccountGuard::new(Arc::new(bot_config), metrics, circuit_breaker);
+
+        let trade = guard.trade_slots.try_acquire().unwrap();
+        assert!(guard.try_begin_rebalance().is_none());
+        drop(trade);
+
+        let _rebalance = guard.try_begin_rebalance().unwrap();
+        assert!(guard.trade_slots.try_acquire().is_err());
+    }
+}
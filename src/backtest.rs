@@ -0,0 +1,355 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use anyhow::Context;
+use chrono::{TimeZone, Utc};
+use log::{debug, info, warn};
+use serde_json::Value;
+use tokio::sync::RwLock;
+use crate::coordinator::{check_price_freshness, evaluate_opportunity, Evaluation, TRADING_COOLDOWN};
+use crate::profit_model::{ProfitEstimate, ProfitModel};
+use crate::services::feed_recorder::RecordedEvent;
+use crate::services::orderbook_stream::{apply_orderbook_message, OrderbookMessage, PerpOrderbook, PriceInfo, publish_top_of_book};
+use crate::services::price_source::{OrderbookPriceSource, PriceQuote, QuoteSide};
+use crate::services::trading_config::{BotConfig, TradingConfig};
+use crate::trade_sequence::Direction;
+
+#[derive(Debug, Clone)]
+struct SimulatedTrade {
+    timestamp_ms: i64,
+    pair: String,
+    direction: Direction,
+    perp_price: f64,
+    swap_price: f64,
+    estimate: ProfitEstimate,
+    // realized: fees and tx costs but no slippage - fills happen at the recorded prices
+    realized_pnl: f64,
+}
+
+// the recorded orderbook messages are replayed into the same book and price source the coordinator uses
+struct PairSimulation {
+    trading_config: TradingConfig,
+    profit_model: ProfitModel,
+    orderbook: Arc<RwLock<PerpOrderbook>>,
+    highest_bid_price: Arc<RwLock<Option<PriceInfo>>>,
+    lowest_ask_price: Arc<RwLock<Option<PriceInfo>>>,
+    perp_source: OrderbookPriceSource,
+    swap_buy: Option<PriceQuote>,
+    swap_sell: Option<PriceQuote>,
+    last_trade_ms: HashMap<Direction, i64>,
+    // base ui
+    perp_position: f64,
+    spot_position: f64,
+}
+
+impl PairSimulation {
+
+    fn new(trading_config: TradingConfig, profit_model: ProfitModel) -> Self {
+        let orderbook = Arc::new(RwLock::new(PerpOrderbook::default()));
+        let highest_bid_price = Arc::new(RwLock::new(None));
+        let lowest_ask_price = Arc::new(RwLock::new(None));
+        PairSimulation {
+            trading_config,
+            profit_model,
+            perp_source: OrderbookPriceSource::new(orderbook.clone(), highest_bid_price.clone(), lowest_ask_price.clone()),
+            orderbook,
+            highest_bid_price,
+            lowest_ask_price,
+            swap_buy: None,
+            swap_sell: None,
+            last_trade_ms: HashMap::new(),
+            perp_position: 0.0,
+            spot_position: 0.0,
+        }
+    }
+
+    fn in_cooldown(&self, direction: Direction, timestamp_ms: i64) -> bool {
+        self.last_trade_ms.get(&direction)
+            .map(|last| timestamp_ms - last < TRADING_COOLDOWN.as_millis() as i64)
+            .unwrap_or(false)
+    }
+
+    // the book is stamped with the replay time instead of the time the message was applied
+    async fn apply_orderbook(&self, raw: &str, observed_at: Instant) -> anyhow::Result<()> {
+        let plain: Value = serde_json::from_str(raw).context("Can't parse orderbook message")?;
+        let mut book = self.orderbook.write().await;
+        let write_version = match apply_orderbook_message(&mut book, &plain)? {
+            OrderbookMessage::Checkpoint { write_version, .. } | OrderbookMessage::Update { write_version, .. } => write_version,
+            OrderbookMessage::Other => return Ok(()),
+        };
+        book.updated_at = Some(observed_at);
+        book.alive_at = Some(observed_at);
+        publish_top_of_book(&book, write_version, &self.highest_bid_price, &self.lowest_ask_price).await;
+        Ok(())
+    }
+
+    // same checks as the coordinator loops, in the same order; the cluster slot is not recorded
+    async fn evaluate(&mut self, timestamp_ms: i64, now: Instant) -> Vec<SimulatedTrade> {
+        let mut trades = vec![];
+        let threshold = self.trading_config.perp_allowance_threshold_base_ui;
+
+        for direction in [Direction::Swap2Perp, Direction::Perp2Swap] {
+            let (swap_quote, allowed) = match direction {
+                Direction::Swap2Perp => (self.swap_buy.clone(), self.perp_position >= -threshold),
+                Direction::Perp2Swap => (self.swap_sell.clone(), self.perp_position <= threshold),
+            };
+            let Some(swap_quote) = swap_quote else { continue; };
+            if self.in_cooldown(direction, timestamp_ms) {
+                continue;
+            }
+            let evaluation = match evaluate_opportunity(direction, &self.trading_config, &self.profit_model, swap_quote, &self.perp_source).await {
+                Ok(evaluation) => evaluation,
+                Err(err) => {
+                    debug!("{} {}: no evaluation: {:#}", format_timestamp(timestamp_ms), direction, err);
+                    continue;
+                }
+            };
+            if !evaluation.should_trade || !allowed {
+                continue;
+            }
+            if let Err(staleness) = check_price_freshness(&self.trading_config, &evaluation, None, now) {
+                debug!("{} {}: skipped: {}", format_timestamp(timestamp_ms), direction, staleness);
+                continue;
+            }
+
+            let base_qty = self.trading_config.base_qty_ui;
+            match direction {
+                Direction::Swap2Perp => {
+                    self.perp_position -= base_qty;
+                    self.spot_position += base_qty;
+                }
+                Direction::Perp2Swap => {
+                    self.perp_position += base_qty;
+                    self.spot_position -= base_qty;
+                }
+            }
+            self.last_trade_ms.insert(direction, timestamp_ms);
+            trades.push(self.simulated_trade(timestamp_ms, direction, &evaluation));
+        }
+
+        trades
+    }
+
+    fn simulated_trade(&self, timestamp_ms: i64, direction: Direction, evaluation: &Evaluation) -> SimulatedTrade {
+        SimulatedTrade {
+            timestamp_ms,
+            pair: self.trading_config.pair_name(),
+            direction,
+            perp_price: evaluation.perp.price,
+            swap_price: evaluation.swap.price,
+            estimate: evaluation.estimate,
+            realized_pnl: evaluation.estimate.net_pnl + evaluation.estimate.slippage_cost,
+        }
+    }
+}
+
+// recorded swap quotes only carry the price - enough for evaluate_opportunity
+fn recorded_swap_quote(side: QuoteSide, price: f64, base_quantity_ui: f64, price_impact_pct: f64, route_fee_ui: f64,
+                       observed_at: Instant) -> PriceQuote {
+    PriceQuote {
+        side,
+        price,
+        base_quantity_ui,
+        price_impact_pct,
+        fee_ui: route_fee_ui,
+        observed_at,
+        slot: None,
+        swap_quote: None,
+    }
+}
+
+struct BacktestResult {
+    pairs: Vec<PairSimulation>,
+    trades: Vec<SimulatedTrade>,
+    event_count: usize,
+    first_timestamp_ms: i64,
+    last_timestamp_ms: i64,
+}
+
+pub async fn run_backtest(bot_config: &BotConfig, input_path: &str, perp_taker_fee: f64) -> anyhow::Result<()> {
+    let file = File::open(input_path)
+        .with_context(|| format!("Can't open recorded feed <{}>", input_path))?;
+    let result = replay(bot_config, BufReader::new(file), perp_taker_fee).await?;
+    print_report(&result);
+    Ok(())
+}
+
+async fn replay(bot_config: &BotConfig, input: impl BufRead, perp_taker_fee: f64) -> anyhow::Result<BacktestResult> {
+    let mut pairs: Vec<PairSimulation> = bot_config.pairs.iter()
+        .map(|pair| PairSimulation::new(pair.clone(), ProfitModel::from_fees(perp_taker_fee, pair, bot_config)))
+        .collect();
+
+    let mut trades: Vec<SimulatedTrade> = vec![];
+    let mut event_count = 0;
+    let mut first_timestamp_ms = None;
+    let mut last_timestamp_ms = 0;
+    // recorded times are mapped onto a clock starting with the replay
+    let replay_started_at = Instant::now();
+
+    for (line_number, line) in input.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let event: RecordedEvent = match serde_json::from_str(&line) {
+            Ok(event) => event,
+            Err(err) => {
+                warn!("skipping invalid record in line {}: {}", line_number + 1, err);
+                continue;
+            }
+        };
+        event_count += 1;
+        let timestamp_ms = event.timestamp_ms();
+        let replay_offset_ms = timestamp_ms - *first_timestamp_ms.get_or_insert(timestamp_ms);
+        last_timestamp_ms = timestamp_ms;
+        let now = replay_started_at + Duration::from_millis(replay_offset_ms.max(0) as u64);
+
+        let pair = match &event {
+            RecordedEvent::Orderbook { market, raw, .. } => {
+                let Some(pair) = pairs.iter_mut().find(|pair| &pair.trading_config.market == market) else { continue; };
+                pair.apply_orderbook(raw, now).await
+                    .with_context(|| format!("Can't apply orderbook message in line {}", line_number + 1))?;
+                pair
+            }
+            RecordedEvent::SwapBuy { pair: pair_name, price, base_quantity_ui, price_impact_pct, route_fee_ui, .. } => {
+                let Some(pair) = pairs.iter_mut().find(|pair| &pair.trading_config.pair_name() == pair_name) else { continue; };
+                pair.swap_buy = Some(recorded_swap_quote(QuoteSide::Buy, *price, *base_quantity_ui, *price_impact_pct, *route_fee_ui, now));
+                pair
+            }
+            RecordedEvent::SwapSell { pair: pair_name, price, base_quantity_ui, price_impact_pct, route_fee_ui, .. } => {
+                let Some(pair) = pairs.iter_mut().find(|pair| &pair.trading_config.pair_name() == pair_name) else { continue; };
+                pair.swap_sell = Some(recorded_swap_quote(QuoteSide::Sell, *price, *base_quantity_ui, *price_impact_pct, *route_fee_ui, now));
+                pair
+            }
+        };
+
+        for trade in pair.evaluate(timestamp_ms, now).await {
+            info!("{} {} {}: perp {:.4} vs swap {:.4}, expected {:.6}, realized {:.6} USDC; position perp {:.4} spot {:.4}",
+                format_timestamp(trade.timestamp_ms), trade.pair, trade.direction, trade.perp_price, trade.swap_price,
+                trade.estimate.net_pnl, trade.realized_pnl, pair.perp_position, pair.spot_position);
+            trades.push(trade);
+        }
+    }
+
+    Ok(BacktestResult {
+        pairs,
+        trades,
+        event_count,
+        first_timestamp_ms: first_timestamp_ms.unwrap_or(0),
+        last_timestamp_ms,
+    })
+}
+
+fn print_report(result: &BacktestResult) {
+    let BacktestResult { pairs, trades, event_count, first_timestamp_ms, last_timestamp_ms } = result;
+    println!("Backtest replayed {} events from {} to {} ({:?})", event_count,
+        format_timestamp(*first_timestamp_ms), format_timestamp(*last_timestamp_ms),
+        Duration::from_millis((last_timestamp_ms - first_timestamp_ms).max(0) as u64));
+
+    println!();
+    println!("{:<24} {:<20} {:<11} {:>12} {:>12} {:>12} {:>12} {:>12}",
+        "time", "pair", "direction", "perp", "swap", "expected", "realized", "cum. pnl");
+    let mut cumulative_pnl = 0.0;
+    for trade in trades.iter() {
+        cumulative_pnl += trade.realized_pnl;
+        println!("{:<24} {:<20} {:<11} {:>12.4} {:>12.4} {:>12.6} {:>12.6} {:>12.6}",
+            format_timestamp(trade.timestamp_ms), trade.pair, trade.direction.to_string(), trade.perp_price, trade.swap_price,
+            trade.estimate.net_pnl, trade.realized_pnl, cumulative_pnl);
+    }
+
+    println!();
+    for pair in pairs.iter() {
+        let pair_name = pair.trading_config.pair_name();
+        let pair_trades = trades.iter().filter(|trade| trade.pair == pair_name).collect::<Vec<_>>();
+        println!("{}: {} trades, realized pnl {:.6} USDC, final position perp {:.4} spot {:.4}",
+            pair_name, pair_trades.len(), pair_trades.iter().map(|trade| trade.realized_pnl).sum::<f64>(),
+            pair.perp_position, pair.spot_position);
+    }
+    println!("total: {} trades, realized pnl {:.6} USDC", trades.len(), cumulative_pnl);
+}
+
+fn format_timestamp(timestamp_ms: i64) -> String {
+    Utc.timestamp_millis_opt(timestamp_ms).single()
+        .map(|time| time.format("%Y-%m-%d %H:%M:%S%.3f").to_string())
+        .unwrap_or_else(|| timestamp_ms.to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::services::feed_recorder::FeedRecorder;
+    use crate::services::trading_config::test_trading_config;
+    use super::*;
+
+    const MARKET: &str = "ESdnpnNLgTkBCZRuTJkZLi5wKEZ2z47SG3PJrhundSQ2";
+
+    fn bot_config() -> BotConfig {
+        let mut bot_config: BotConfig = toml::from_str(r#"
+            sol_price_ui_estimate = 20.0
+            pair = []
+        "#).unwrap();
+        bot_config.pairs.push(test_trading_config());
+        bot_config
+    }
+
+    fn orderbook(timestamp_ms: i64, raw: Value) -> RecordedEvent {
+        RecordedEvent::Orderbook { timestamp_ms, market: MARKET.to_string(), raw: raw.to_string() }
+    }
+
+    fn swap_buy(timestamp_ms: i64, price: f64) -> RecordedEvent {
+        RecordedEvent::SwapBuy { timestamp_ms, pair: "SOL-PERP/SOL".to_string(), price, base_quantity_ui: 1.0, price_impact_pct: 0.0, route_fee_ui: 0.0 }
+    }
+
+    fn swap_sell(timestamp_ms: i64, price: f64) -> RecordedEvent {
+        RecordedEvent::SwapSell { timestamp_ms, pair: "SOL-PERP/SOL".to_string(), price, base_quantity_ui: 1.0, price_impact_pct: 0.0, route_fee_ui: 0.0 }
+    }
+
+    #[tokio::test]
+    async fn replayed_feed_trades_like_the_coordinator() {
+        let feed = [
+            orderbook(1_000, serde_json::json!({"market": MARKET, "bids": [[102.0, 5.0]], "asks": [[102.5, 5.0]], "slot": 1, "write_version": 1})),
+            // buy 100 on the swap, sell 102 on the perp
+            swap_buy(1_100, 100.0),
+            // cooldown
+            swap_buy(1_200, 100.0),
+            // the last swap buy quote is stale by now
+            orderbook(7_000, serde_json::json!({"market": MARKET, "side": "bid", "update": [[101.0, 1.0]], "slot": 2, "write_version": 2})),
+            // buy 102.5 on the perp, sell 104 on the swap
+            swap_sell(7_100, 104.0),
+        ].iter().map(|event| serde_json::to_string(event).unwrap()).collect::<Vec<_>>().join("\n");
+
+        let bot_config = bot_config();
+        let result = replay(&bot_config, feed.as_bytes(), 0.0004).await.unwrap();
+        assert_eq!(5, result.event_count);
+
+        let trades = result.trades.iter().map(|trade| (trade.timestamp_ms, trade.direction, trade.perp_price, trade.swap_price)).collect::<Vec<_>>();
+        assert_eq!(vec![(1_100, Direction::Swap2Perp, 102.0, 100.0), (7_100, Direction::Perp2Swap, 102.5, 104.0)], trades);
+
+        // no slippage in the replay: gross minus perp fees and tx costs
+        let tx_cost_ui = ProfitModel::from_fees(0.0004, &bot_config.pairs[0], &bot_config).tx_cost_ui;
+        let expected_pnl = (2.0 - 102.0 * 0.0004 - tx_cost_ui) + (1.5 - 102.5 * 0.0004 - tx_cost_ui);
+        let realized_pnl: f64 = result.trades.iter().map(|trade| trade.realized_pnl).sum();
+        assert!((expected_pnl - realized_pnl).abs() < 1e-9, "realized {} expected {}", realized_pnl, expected_pnl);
+        assert_eq!(0.0, result.pairs[0].perp_position);
+        assert_eq!(0.0, result.pairs[0].spot_position);
+    }
+
+    #[tokio::test]
+    async fn recorded_feed_can_be_replayed() {
+        let record_dir = std::env::temp_dir().join(format!("backtest-replay-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&record_dir);
+        let recorder = FeedRecorder::start(record_dir.to_str().unwrap()).unwrap();
+        let book = serde_json::json!({"market": MARKET, "bids": [[102.0, 5.0]], "asks": [[102.5, 5.0]], "slot": 1, "write_version": 1});
+        recorder.record_orderbook_message(MARKET, &book.to_string());
+        recorder.record_swap_quote("SOL-PERP/SOL", &recorded_swap_quote(QuoteSide::Buy, 100.0, 1.0, 0.0, 0.0, Instant::now()));
+        recorder.flush().await;
+
+        let record_file = std::fs::read_dir(&record_dir).unwrap().next().unwrap().unwrap().path();
+        let result = replay(&bot_config(), BufReader::new(File::open(record_file).unwrap()), 0.0004).await.unwrap();
+        assert_eq!(2, result.event_count);
+        let trades = result.trades.iter().map(|trade| (trade.direction, trade.perp_price, trade.swap_price)).collect::<Vec<_>>();
+        assert_eq!(vec![(Direction::Swap2Perp, 102.0, 100.0)], trades);
+        std::fs::remove_dir_all(&record_dir).unwrap();
+    }
+}
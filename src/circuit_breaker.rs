@@ -0,0 +1,412 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use async_trait::async_trait;
+use log::{error, info, warn};
+use crate::metrics::Metrics;
+use crate::services::trading_config::CircuitBreakerConfig;
+use crate::trade_sequence::{LegResult, TradeLegs, TradeOutcome, TradeSequence};
+
+const TRADE_RATE_WINDOW: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum HaltReason {
+    ConsecutiveFailedTransactions(u32),
+    RealizedLoss(f64),
+    TradeRate(usize),
+    StalePriceFeed(String),
+    RpcErrorRate(f64),
+    KillSwitch(String),
+}
+
+impl HaltReason {
+    // metric label
+    fn label(&self) -> &'static str {
+        match self {
+            HaltReason::ConsecutiveFailedTransactions(_) => "consecutive_failed_transactions",
+            HaltReason::RealizedLoss(_) => "realized_loss",
+            HaltReason::TradeRate(_) => "trade_rate",
+            HaltReason::StalePriceFeed(_) => "stale_price_feed",
+            HaltReason::RpcErrorRate(_) => "rpc_error_rate",
+            HaltReason::KillSwitch(_) => "kill_switch",
+        }
+    }
+}
+
+impl fmt::Display for HaltReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HaltReason::ConsecutiveFailedTransactions(count) => write!(f, "{} consecutive failed transactions", count),
+            HaltReason::RealizedLoss(loss) => write!(f, "realized loss of {:.6} USDC within the loss window", loss),
+            HaltReason::TradeRate(count) => write!(f, "{} trades within the last hour", count),
+            HaltReason::StalePriceFeed(feed) => write!(f, "no fresh prices from feed '{}'", feed),
+            HaltReason::RpcErrorRate(rate) => write!(f, "RPC error rate of {:.0}%", rate * 100.0),
+            HaltReason::KillSwitch(source) => write!(f, "kill switch ({})", source),
+        }
+    }
+}
+
+#[derive(Default)]
+struct BreakerState {
+    // sticky until reset
+    halted: Option<HaltReason>,
+    consecutive_failed_transactions: u32,
+    // finished trade sequences
+    realized: VecDeque<(Instant, f64)>,
+    trades_started: VecDeque<Instant>,
+    // true = success
+    rpc_results: VecDeque<(Instant, bool)>,
+    // feed name -> last observation
+    price_feeds: HashMap<String, Instant>,
+}
+
+// account-wide brake: once a limit is hit trading stays halted until reset() is called
+pub struct CircuitBreaker {
+    config: CircuitBreakerConfig,
+    state: Mutex<BreakerState>,
+    metrics: Arc<Metrics>,
+}
+
+impl CircuitBreaker {
+
+    pub fn new(config: CircuitBreakerConfig, metrics: Arc<Metrics>) -> Self {
+        metrics.set_gauge("trading_halted", &[], 0.0);
+        CircuitBreaker {
+            config,
+            state: Mutex::new(BreakerState::default()),
+            metrics,
+        }
+    }
+
+    // checks the limits which depend on time (kill switch file, silent price feeds) and returns the halt reason if any
+    pub fn halt_reason(&self) -> Option<HaltReason> {
+        if let Some(kill_switch_file) = &self.config.kill_switch_file {
+            if Path::new(kill_switch_file).exists() {
+                self.trip(HaltReason::KillSwitch(format!("file {}", kill_switch_file)));
+            }
+        }
+        self.check_price_feeds_at(Instant::now());
+        self.state.lock().unwrap().halted.clone()
+    }
+
+    pub fn is_trading_allowed(&self) -> bool {
+        self.halt_reason().is_none()
+    }
+
+    pub fn trip(&self, reason: HaltReason) {
+        let mut state = self.state.lock().unwrap();
+        if state.halted.is_some() {
+            return;
+        }
+        error!("Circuit breaker tripped - trading halted until reset: {}", reason);
+        self.metrics.set_gauge("trading_halted", &[], 1.0);
+        self.metrics.inc_counter("circuit_breaker_trips_total", &[("reason", reason.label())]);
+        state.halted = Some(reason);
+    }
+
+    // explicit operator action; counters and windows start over
+    pub fn reset(&self) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(reason) = state.halted.take() {
+            info!("Circuit breaker reset (was halted: {})", reason);
+        }
+        let price_feeds = std::mem::take(&mut state.price_feeds);
+        *state = BreakerState::default();
+        // feeds keep running; restart their clocks
+        let now = Instant::now();
+        state.price_feeds = price_feeds.into_keys().map(|feed| (feed, now)).collect();
+        self.metrics.set_gauge("trading_halted", &[], 0.0);
+    }
+
+    pub fn record_price_observation(&self, feed: &str, observed_at: Instant) {
+        let mut state = self.state.lock().unwrap();
+        let last = state.price_feeds.entry(feed.to_string()).or_insert(observed_at);
+        if observed_at > *last {
+            *last = observed_at;
+        }
+    }
+
+    pub fn record_rpc_result(&self, success: bool) {
+        self.record_rpc_result_at(Instant::now(), success);
+    }
+
+    pub fn record_trade_started(&self) {
+        self.record_trade_started_at(Instant::now());
+    }
+
+    pub fn record_trade_finished(&self, sequence: &TradeSequence) {
+        // an unhedged sequence has an open position, its cash flow is not a realized PnL
+        let realized = match sequence.outcome() {
+            Some(TradeOutcome::Hedged) | Some(TradeOutcome::Unwound) => match sequence.realized_cash_flow() {
+                Some(realized) => realized,
+                None => {
+                    // counting only the known legs would book a whole leg as loss or profit
+                    warn!("trade sequence {}: a leg traded without a known fill, realized PnL not counted", sequence.id);
+                    return;
+                }
+            },
+            Some(TradeOutcome::Aborted) | Some(TradeOutcome::Unhedged) | None => 0.0,
+        };
+        self.record_realized_at(Instant::now(), realized);
+    }
+
+    pub fn record_transaction(&self, success: bool) {
+        let count = {
+            let mut state = self.state.lock().unwrap();
+            if success {
+                state.consecutive_failed_transactions = 0;
+                return;
+            }
+            state.consecutive_failed_transactions += 1;
+            state.consecutive_failed_transactions
+        };
+        if count >= self.config.max_consecutive_failed_transactions {
+            self.trip(HaltReason::ConsecutiveFailedTransactions(count));
+        }
+    }
+
+    fn record_rpc_result_at(&self, now: Instant, success: bool) {
+        let window = Duration::from_secs(self.config.rpc_error_window_secs);
+        let error_rate = {
+            let mut state = self.state.lock().unwrap();
+            state.rpc_results.push_back((now, success));
+            while state.rpc_results.front().map(|(at, _)| now.duration_since(*at) > window).unwrap_or(false) {
+                state.rpc_results.pop_front();
+            }
+            if state.rpc_results.len() < self.config.min_rpc_samples {
+                return;
+            }
+            let failed = state.rpc_results.iter().filter(|(_, success)| !success).count();
+            failed as f64 / state.rpc_results.len() as f64
+        };
+        if error_rate > self.config.max_rpc_error_rate {
+            self.trip(HaltReason::RpcErrorRate(error_rate));
+        }
+    }
+
+    fn record_trade_started_at(&self, now: Instant) {
+        let count = {
+            let mut state = self.state.lock().unwrap();
+            state.trades_started.push_back(now);
+            while state.trades_started.front().map(|at| now.duration_since(*at) > TRADE_RATE_WINDOW).unwrap_or(false) {
+                state.trades_started.pop_front();
+            }
+            state.trades_started.len()
+        };
+        if count >= self.config.max_trades_per_hour {
+            self.trip(HaltReason::TradeRate(count));
+        }
+    }
+
+    fn record_realized_at(&self, now: Instant, realized: f64) {
+        let window = Duration::from_secs(self.config.loss_window_secs);
+        let total = {
+            let mut state = self.state.lock().unwrap();
+            state.realized.push_back((now, realized));
+            while state.realized.front().map(|(at, _)| now.duration_since(*at) > window).unwrap_or(false) {
+                state.realized.pop_front();
+            }
+            state.realized.iter().map(|(_, pnl)| pnl).sum::<f64>()
+        };
+        self.metrics.set_gauge("realized_pnl_window_ui", &[], total);
+        if total < -self.config.max_realized_loss_ui {
+            self.trip(HaltReason::RealizedLoss(-total));
+        }
+    }
+
+    fn check_price_feeds_at(&self, now: Instant) {
+        let max_silence = Duration::from_millis(self.config.max_price_feed_silence_ms);
+        let stale_feed = {
+            let state = self.state.lock().unwrap();
+            state.price_feeds.iter()
+                .find(|(_, last)| now.saturating_duration_since(**last) > max_silence)
+                .map(|(feed, _)| feed.clone())
+        };
+        if let Some(feed) = stale_feed {
+            self.trip(HaltReason::StalePriceFeed(feed));
+        }
+    }
+}
+
+// SIGUSR1 halts trading (manual kill switch), SIGUSR2 resets the circuit breaker
+#[cfg(unix)]
+pub async fn listen_for_kill_switch_signals(circuit_breaker: Arc<CircuitBreaker>) {
+    use tokio::signal::unix::{signal, SignalKind};
+    let (Ok(mut halt), Ok(mut reset)) = (signal(SignalKind::user_defined1()), signal(SignalKind::user_defined2())) else {
+        warn!("Can't install kill switch signal handlers");
+        return;
+    };
+    loop {
+        tokio::select! {
+            _ = halt.recv() => circuit_breaker.trip(HaltReason::KillSwitch("SIGUSR1".to_string())),
+            _ = reset.recv() => circuit_breaker.reset(),
+        }
+    }
+}
+
+#[cfg(not(unix))]
+pub async fn listen_for_kill_switch_signals(_circuit_breaker: Arc<CircuitBreaker>) {
+}
+
+// counts every leg transaction (incl. retries and unwinds) of a trade sequence
+pub struct BreakerLegs<'a> {
+    pub legs: &'a dyn TradeLegs,
+    pub circuit_breaker: &'a CircuitBreaker,
+}
+
+impl BreakerLegs<'_> {
+    fn record(&self, result: LegResult) -> LegResult {
+        self.circuit_breaker.record_transaction(result.is_ok());
+        result
+    }
+}
+
+#[async_trait]
+impl TradeLegs for BreakerLegs<'_> {
+    async fn leg_a(&self) -> LegResult {
+        self.record(self.legs.leg_a().await)
+    }
+
+    async fn leg_b(&self, base_quantity_ui: f64) -> LegResult {
+        self.record(self.legs.leg_b(base_quantity_ui).await)
+    }
+
+    async fn unwind_a(&self, base_quantity_ui: f64) -> LegResult {
+        self.record(self.legs.unwind_a(base_quantity_ui).await)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use solana_sdk::signature::Signature;
+    use crate::trade_sequence::{execute_trade_sequence, Direction, LegReport, RetryBudget, TradeJournal};
+    use super::*;
+
+    fn breaker() -> CircuitBreaker {
+        let config = CircuitBreakerConfig {
+            max_consecutive_failed_transactions: 3,
+            max_realized_loss_ui: 10.0,
+            max_trades_per_hour: 5,
+            min_rpc_samples: 4,
+            ..CircuitBreakerConfig::default()
+        };
+        CircuitBreaker::new(config, Arc::new(Metrics::default()))
+    }
+
+    #[test]
+    fn halt_after_consecutive_failed_transactions() {
+        let breaker = breaker();
+        breaker.record_transaction(false);
+        breaker.record_transaction(false);
+        breaker.record_transaction(true);
+        breaker.record_transaction(false);
+        breaker.record_transaction(false);
+        assert!(breaker.is_trading_allowed());
+
+        breaker.record_transaction(false);
+        assert_eq!(Some(HaltReason::ConsecutiveFailedTransactions(3)), breaker.halt_reason());
+    }
+
+    #[test]
+    fn halt_is_sticky_until_reset() {
+        let breaker = breaker();
+        let now = Instant::now();
+        breaker.record_realized_at(now, -6.0);
+        breaker.record_realized_at(now, -6.0);
+        assert_eq!(Some(HaltReason::RealizedLoss(12.0)), breaker.halt_reason());
+
+        // profits don't lift the halt
+        breaker.record_realized_at(now, 20.0);
+        assert!(!breaker.is_trading_allowed());
+
+        breaker.reset();
+        assert!(breaker.is_trading_allowed());
+    }
+
+    #[test]
+    fn halt_on_trade_rate() {
+        let breaker = breaker();
+        let start = Instant::now();
+        for minute in [0, 10, 20, 30] {
+            breaker.record_trade_started_at(start + Duration::from_secs(60 * minute));
+        }
+        // first trade left the window
+        breaker.record_trade_started_at(start + Duration::from_secs(61 * 60));
+        assert!(breaker.is_trading_allowed());
+
+        breaker.record_trade_started_at(start + Duration::from_secs(62 * 60));
+        assert_eq!(Some(HaltReason::TradeRate(5)), breaker.halt_reason());
+    }
+
+    #[test]
+    fn halt_on_rpc_error_rate_with_enough_samples() {
+        let breaker = breaker();
+        let now = Instant::now();
+        breaker.record_rpc_result_at(now, false);
+        breaker.record_rpc_result_at(now, false);
+        breaker.record_rpc_result_at(now, false);
+        assert!(breaker.is_trading_allowed());
+
+        breaker.record_rpc_result_at(now, true);
+        assert_eq!(Some(HaltReason::RpcErrorRate(0.75)), breaker.halt_reason());
+    }
+
+    #[test]
+    fn halt_on_silent_price_feed() {
+        let breaker = breaker();
+        let now = Instant::now();
+        breaker.record_price_observation("orderbook", now);
+        breaker.check_price_feeds_at(now + Duration::from_secs(10));
+        assert!(breaker.is_trading_allowed());
+
+        breaker.check_price_feeds_at(now + Duration::from_secs(61));
+        assert_eq!(Some(HaltReason::StalePriceFeed("orderbook".to_string())), breaker.halt_reason());
+    }
+
+    // swap leg bought for 100 USDC, the perp short's fill was missed by the fills feed
+    struct MissedFillLegs;
+
+    #[async_trait]
+    impl TradeLegs for MissedFillLegs {
+        async fn leg_a(&self) -> LegResult {
+            Ok(LegReport {
+                signature: Signature::default(),
+                requested_base_ui: 1.0,
+                executed_base_ui: 1.0,
+                perp_fill: None,
+                swap_base_delta: Some(1.0),
+                swap_quote_delta: Some(-100.0),
+            })
+        }
+
+        async fn leg_b(&self, base_quantity_ui: f64) -> LegResult {
+            Ok(LegReport {
+                signature: Signature::default(),
+                requested_base_ui: base_quantity_ui,
+                executed_base_ui: base_quantity_ui,
+                perp_fill: None,
+                swap_base_delta: None,
+                swap_quote_delta: None,
+            })
+        }
+
+        async fn unwind_a(&self, _base_quantity_ui: f64) -> LegResult {
+            unreachable!()
+        }
+    }
+
+    #[tokio::test]
+    async fn missed_perp_fill_is_not_a_loss() {
+        let breaker = breaker();
+        let retry_budget = RetryBudget { max_attempts: 1, delay: Duration::ZERO };
+        let sequence = execute_trade_sequence(TradeSequence::new(1, "SOL-PERP/SOL".to_string(), Direction::Swap2Perp),
+            &MissedFillLegs, retry_budget, &TradeJournal::default()).await;
+        assert_eq!(Some(TradeOutcome::Hedged), sequence.outcome());
+        assert_eq!(None, sequence.realized_cash_flow());
+
+        breaker.record_trade_finished(&sequence);
+        assert!(breaker.is_trading_allowed());
+    }
+}
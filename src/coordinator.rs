@@ -1,255 +1,835 @@
+use std::fmt;
+use std::future::Future;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+use anyhow::bail;
+use async_trait::async_trait;
 use chrono::Utc;
-use futures::join;
 
-use log::{debug, info, trace, warn};
-use mpsc::unbounded_channel;
-use tokio::sync::{mpsc, RwLock};
-use tokio::sync::mpsc::UnboundedReceiver;
+use log::{debug, error, info, trace, warn};
+use tokio::sync::{watch, RwLock};
 use tokio::time::{interval, sleep};
 use crate::MangoClientRef;
-use crate::services::asset_price_swap;
-
-use crate::services::asset_price_swap::{SwapBuyPrice, SwapSellPrice};
-use crate::services::orderbook_stream::{listen_perp_market_feed, PriceInfo};
-use crate::services::perp_orders::{calc_perp_position_allowance, perp_ask_asset, perp_bid_asset, perp_bid_blocking_until_fill, PerpAllowance};
-use crate::services::swap_orders::{swap_buy_asset, swap_sell_asset};
-use crate::services::trading_config::*;
+use crate::account_guard::AccountGuard;
+use crate::circuit_breaker::{BreakerLegs, CircuitBreaker};
+use crate::inventory::InventoryManager;
+use crate::metrics::Metrics;
+use crate::profit_model::{ProfitEstimate, ProfitModel};
+use crate::services::feed_recorder::FeedRecorder;
+use crate::services::fills_stream::FillsFeed;
+use crate::services::orderbook_stream::{listen_perp_market_feed, PerpOrderbook, PriceInfo, TopOfBookEvents};
+use crate::services::price_source::{JupiterPriceSource, OrderbookPriceSource, PriceQuote, PriceSource, QuoteSide};
+use crate::services::perp_orders::{calc_perp_position_allowance, perp_ask_asset, perp_bid_asset, perp_position_base_ui, perp_position_base_ui_at_slot, PerpAllowance};
+use crate::services::jupiter_quote::{JupiterQuoter, SwapQuote};
+use crate::services::swap_orders::{check_swap_balance_delta, swap_buy_asset, swap_quoted_route, swap_positions_ui, swap_positions_ui_at_slot, swap_sell_asset};
+use crate::services::onchain_orderbook::listen_onchain_perp_market;
+use crate::services::slot_tracker::SlotTracker;
+use crate::services::trading_config::{BotConfig, OrderbookSource, TradingConfig};
+use crate::services::transactions::TransactionConfirmer;
+use crate::supervisor::{RESTART_WITH_BACKOFF, Supervisor};
+use crate::trade_sequence::{COMPLETE_FILL_RATIO, Direction, execute_trade_sequence, LegError, LegReport, LegResult, RetryBudget, TradeJournal, TradeLegs, TradeSequence};
+use solana_sdk::commitment_config::CommitmentConfig;
+use solana_sdk::signature::Signature;
 
 const STARTUP_DELAY: Duration = Duration::from_secs(2);
 
-const MARKET_SCAN_INTERVAL: Duration =Duration::from_millis(500);
+// the loops are woken by price events; re-evaluate unchanged prices this often (e.g. after the trade cooldown)
+const RESCAN_INTERVAL: Duration = Duration::from_secs(5);
 
 // time to wait after trade (per direction)
-const TRADING_COOLDOWN: Duration = Duration::from_secs(5);
+pub const TRADING_COOLDOWN: Duration = Duration::from_secs(5);
 
+// orderbook state shared by the feed listener, the price sources and the inventory manager
 struct Coordinator {
-    // swap price from router service
-    buy_price_stream: UnboundedReceiver<SwapBuyPrice>,
-    sell_price_stream: UnboundedReceiver<SwapSellPrice>,
-    // orderbook
+    orderbook_shared: Arc<RwLock<PerpOrderbook>>,
     last_bid_price_shared: Arc<RwLock<Option<PriceInfo>>>,
     last_ask_price_shared: Arc<RwLock<Option<PriceInfo>>>,
 }
 
 
-pub async fn run_coordinator_service(mango_client: Arc<MangoClientRef>, dry_run: bool) {
+// registers the tasks of all pairs with the supervisor
+pub fn start_coordinator_service(mango_client: Arc<MangoClientRef>, bot_config: Arc<BotConfig>,
+                                 confirmer: Arc<TransactionConfirmer>, recorder: Option<FeedRecorder>, metrics: Arc<Metrics>,
+                                 circuit_breaker: Arc<CircuitBreaker>, slot_tracker: Arc<SlotTracker>, account_guard: Arc<AccountGuard>,
+                                 trade_journal: Arc<TradeJournal>, supervisor: &Supervisor, dry_run: bool) {
 
-    let (buy_price_xwrite, mut buy_price_xread) = unbounded_channel();
-    let (sell_price_xwrite, mut sell_price_xread) = unbounded_channel();
+    let quoter = Arc::new(JupiterQuoter::new(&bot_config.jupiter_v6_url));
 
-    let mut coo = Coordinator {
-        buy_price_stream: buy_price_xread,
-        sell_price_stream: sell_price_xread,
+    for pair in &bot_config.pairs {
+        let profit_model = ProfitModel::new(&mango_client.context, pair, &bot_config);
+        info!("{}: profit model {:?}", pair.pair_name(), profit_model);
+        start_pair_coordinator(
+            mango_client.clone(), Arc::new(pair.clone()), profit_model, bot_config.orderbook_source, quoter.clone(),
+            account_guard.clone(), metrics.clone(), circuit_breaker.clone(), slot_tracker.clone(), trade_journal.clone(),
+            confirmer.clone(), recorder.clone(), supervisor, dry_run);
+    }
+}
+
+// one independent coordinator per market pair; all pairs share the mango client and account guard
+// feeds are restarted when they fail; the trading loops and the inventory manager escalate to a shutdown
+fn start_pair_coordinator(mango_client: Arc<MangoClientRef>, trading_config: Arc<TradingConfig>, profit_model: ProfitModel,
+                          orderbook_source: OrderbookSource, quoter: Arc<JupiterQuoter>, account_guard: Arc<AccountGuard>, metrics: Arc<Metrics>,
+                          circuit_breaker: Arc<CircuitBreaker>, slot_tracker: Arc<SlotTracker>, trade_journal: Arc<TradeJournal>,
+                          confirmer: Arc<TransactionConfirmer>, recorder: Option<FeedRecorder>, supervisor: &Supervisor, dry_run: bool) {
+    let pair_name = trading_config.pair_name();
+
+    // latest swap quote per side; each direction loop owns the receiver of its side
+    let (buy_price_xwrite, mut buy_price_xread) = watch::channel(None);
+    let (sell_price_xwrite, mut sell_price_xread) = watch::channel(None);
+    let (buy_price_xwrite, sell_price_xwrite) = (Arc::new(buy_price_xwrite), Arc::new(sell_price_xwrite));
+
+    // initial value is never evaluated - the loops wait for the first change
+    let (top_of_book_events, top_of_book_changed) = watch::channel(Instant::now());
+    let top_of_book_events = Arc::new(top_of_book_events);
+
+    let coo = Coordinator {
+        orderbook_shared: Arc::new(RwLock::new(PerpOrderbook::default())),
         last_bid_price_shared: Arc::new(RwLock::new(None)),
         last_ask_price_shared: Arc::new(RwLock::new(None)),
     };
 
-    let poll_buy_price = tokio::spawn({
-        let mc = mango_client.clone();
-        async move {
-            sleep(STARTUP_DELAY).await;
-            let mut interval = interval(Duration::from_secs(2));
-            loop {
-                let jupiter = mc.jupiter_v4();
-                let price = asset_price_swap::call_buy(&jupiter).await;
-                debug!("swap buy price: {:?}", price);
+    let swap_source: Arc<dyn PriceSource> = Arc::new(JupiterPriceSource::new(quoter.clone(), trading_config.clone()));
+    let perp_source: Arc<dyn PriceSource> = Arc::new(OrderbookPriceSource::new(
+        coo.orderbook_shared.clone(), coo.last_bid_price_shared.clone(), coo.last_ask_price_shared.clone()));
 
-                buy_price_xwrite.send(price).unwrap();
+    let fills_feed = FillsFeed::start(trading_config.market.clone(), supervisor);
 
-                interval.tick().await;
-            }
-        }
+    supervisor.supervise(&format!("{} swap buy poller", pair_name), RESTART_WITH_BACKOFF, {
+        let (swap_source, trading_config, recorder, circuit_breaker) =
+            (swap_source.clone(), trading_config.clone(), recorder.clone(), circuit_breaker.clone());
+        move || poll_swap_quotes(swap_source.clone(), trading_config.clone(), QuoteSide::Buy, recorder.clone(),
+            circuit_breaker.clone(), buy_price_xwrite.clone())
     });
 
-    let poll_sell_price = tokio::spawn({
-        let mc = mango_client.clone();
-        async move {
-            sleep(STARTUP_DELAY).await;
-            let mut interval = interval(Duration::from_secs(2));
-            loop {
-                let jupyter = mc.jupiter_v4();
-                let price = asset_price_swap::call_sell(&jupyter).await;
-                debug!("swap sell price: {:?}", price);
-
-                sell_price_xwrite.send(price).unwrap();
-
-                interval.tick().await;
-            }
-        }
+    supervisor.supervise(&format!("{} swap sell poller", pair_name), RESTART_WITH_BACKOFF, {
+        let (swap_source, trading_config, recorder, circuit_breaker) =
+            (swap_source.clone(), trading_config.clone(), recorder.clone(), circuit_breaker.clone());
+        move || poll_swap_quotes(swap_source.clone(), trading_config.clone(), QuoteSide::Sell, recorder.clone(),
+            circuit_breaker.clone(), sell_price_xwrite.clone())
     });
 
-    // TODO crashing thread should stop the whole program
-    let poll_orderbook = tokio::spawn({
+    supervisor.supervise(&format!("{} orderbook feed", pair_name), RESTART_WITH_BACKOFF, {
+        let mc = mango_client.clone();
+        let orderbook = coo.orderbook_shared.clone();
         let last_bid_price = coo.last_bid_price_shared.clone();
         let last_ask_price = coo.last_ask_price_shared.clone();
-        async move {
-            sleep(STARTUP_DELAY).await;
-            listen_perp_market_feed(MARKET, last_bid_price, last_ask_price).await;
-            warn!("Orderbook WebSocket stream thread exited!");
-        }
+        let trading_config = trading_config.clone();
+        let recorder = recorder.clone();
+        move || listen_orderbook(mc.clone(), orderbook_source, trading_config.clone(), orderbook.clone(),
+            last_bid_price.clone(), last_ask_price.clone(), top_of_book_events.clone(), recorder.clone())
     });
 
     // buy on jupiter, short on eth-perp
-    let main_swap2perp_poller = tokio::spawn({
+    supervisor.supervise_once(&format!("{} swap2perp loop", pair_name), {
         let mc = mango_client.clone();
-        let last_bid_price = coo.last_bid_price_shared.clone();
+        let trading_config = trading_config.clone();
+        let account_guard = account_guard.clone();
+        let trade_journal = trade_journal.clone();
+        let confirmer = confirmer.clone();
+        let fills_feed = fills_feed.clone();
+        let perp_source = perp_source.clone();
+        let quoter = quoter.clone();
+        let circuit_breaker = circuit_breaker.clone();
+        let slot_tracker = slot_tracker.clone();
+        let metrics = metrics.clone();
+        let mut top_of_book_changed = top_of_book_changed.clone();
         async move {
-            let mut poll_interval = interval(MARKET_SCAN_INTERVAL);
+            let mut rescan = interval(RESCAN_INTERVAL);
             let mut throttle = interval(TRADING_COOLDOWN);
-            info!("Entering coordinator JUPITERSWAP->PERP loop (interval={:?}) ...", poll_interval.period());
+            info!("Entering coordinator JUPITERSWAP->PERP loop for {} (rescan={:?}) ...", trading_config.pair_name(), rescan.period());
             loop {
+                let event = tokio::select! {
+                    changed = buy_price_xread.changed() => {
+                        if changed.is_err() {
+                            warn!("{}: swap buy price feed closed", trading_config.pair_name());
+                            return;
+                        }
+                        // quotes replaced during a trade sequence are skipped
+                        let Some(quote) = buy_price_xread.borrow().clone() else {
+                            continue;
+                        };
+                        trace!("swap buy price {:?}", quote);
+                        PriceEvent::SwapQuote(quote.observed_at)
+                    }
+                    changed = top_of_book_changed.changed() => {
+                        if changed.is_err() {
+                            warn!("{}: orderbook feed closed", trading_config.pair_name());
+                            return;
+                        }
+                        let received_at = *top_of_book_changed.borrow();
+                        PriceEvent::TopOfBook(received_at)
+                    }
+                    _ = rescan.tick() => PriceEvent::Rescan,
+                };
 
-                if matches!(calc_perp_position_allowance(mc.clone()).await, PerpAllowance::NoShort) {
-                    debug!("no perp short position allowance, skipping ...");
-                    poll_interval.tick().await;
+                let Some(swap_buy) = buy_price_xread.borrow().clone() else {
                     continue;
-                }
+                };
 
-                let latest_swap_buy = drain_swap_buy_feed(&mut coo.buy_price_stream);
-                debug!("swap latest buy price {:?}", latest_swap_buy);
-
-                let orderbook_bid = last_bid_price.read().await;
-                debug!("orderbook(perp) best bid {:?}", *orderbook_bid);
-
-                if let (Some(perp_bid), Some(swap_buy)) = (*orderbook_bid, latest_swap_buy) {
-                    let profit = (perp_bid.price - swap_buy.price) / swap_buy.price;
-                    let should_trade = should_trade(profit);
-                    info!("{} perp-bid {:.2?} vs swap-buy {:.2?}, expected profit {:.2?}%",
-                        if should_trade { "*" } else { "." },
-                        perp_bid.price, swap_buy.price, 100.0 * profit);
-
-                    if should_trade && !dry_run {
-                        info!("profitable trade swap2perp detected, starting trade sequence ...");
-                        trade_sequence_swap2perp(mc.clone()).await;
-                        throttle.tick().await;
+                // selling the perp walks down the bids
+                let evaluation = match evaluate_opportunity(Direction::Swap2Perp, &trading_config, &profit_model, swap_buy.clone(), perp_source.as_ref()).await {
+                    Ok(evaluation) => {
+                        circuit_breaker.record_price_observation(&format!("{} perp", trading_config.pair_name()), evaluation.perp.observed_at);
+                        evaluation
+                    }
+                    Err(err) => {
+                        debug!("{}: can't price perp leg, skipping: {}", trading_config.pair_name(), err);
+                        continue;
                     }
+                };
+                let latency = event.latency(Instant::now());
+                if let Some(latency) = latency {
+                    metrics.set_gauge("decision_latency_ms", &[("pair", &trading_config.pair_name()), ("direction", "swap2perp")],
+                        latency.as_secs_f64() * 1000.0);
                 }
+                let estimate = evaluation.estimate;
+                info!("{} {}: perp-bid vwap {:.2?} vs swap-buy {:.2?} (size {}, impact {:.3}%), expected net profit {:.2?}% ({}); {} latency {:?}",
+                    if evaluation.should_trade { "*" } else { "." }, trading_config.pair_name(),
+                    evaluation.perp.price, swap_buy.price, swap_buy.base_quantity_ui, 100.0 * swap_buy.price_impact_pct,
+                    100.0 * estimate.net_edge(), format_estimate(&estimate), event, latency);
 
-                poll_interval.tick().await;
+                if !evaluation.should_trade || dry_run {
+                    continue;
+                }
+                let allowance = calc_perp_position_allowance(mc.clone(), &trading_config).await;
+                circuit_breaker.record_rpc_result(allowance.is_ok());
+                match allowance {
+                    Ok(PerpAllowance::NoShort) => {
+                        debug!("{}: no perp short position allowance, skipping ...", trading_config.pair_name());
+                        continue;
+                    }
+                    Ok(_) => {}
+                    Err(err) => {
+                        warn!("{}: can't read the perp position, skipping ...: {:#}", trading_config.pair_name(), err);
+                        continue;
+                    }
+                }
+                if let Err(staleness) = check_price_freshness(&trading_config, &evaluation, slot_tracker.current_slot(), Instant::now()) {
+                    info!("{}: stale prices, skipping swap2perp trade: {}", trading_config.pair_name(), staleness);
+                    metrics.inc_counter("trades_refused_total", &[("pair", &trading_config.pair_name()), ("reason", staleness.label())]);
+                } else if let Some(_permit) = account_guard.try_begin_trade(mc.clone(), &trading_config, Direction::Swap2Perp, swap_buy.price).await {
+                    info!("profitable trade swap2perp detected on {}, starting trade sequence ...", trading_config.pair_name());
+                    trade_sequence_swap2perp(mc.clone(), trading_config.clone(), confirmer.clone(), fills_feed.clone(),
+                        quoter.clone(), swap_buy.swap_quote.clone(), &trade_journal, &circuit_breaker).await;
+                    throttle.tick().await;
+                }
             }
         }
     });
 
     // buy on eth-perp, sell on jupiter
-    let main_perp2swap_poller = tokio::spawn({
+    supervisor.supervise_once(&format!("{} perp2swap loop", pair_name), {
         let mc = mango_client.clone();
-        let last_ask_price = coo.last_ask_price_shared.clone();
+        let trading_config = trading_config.clone();
+        let account_guard = account_guard.clone();
+        let trade_journal = trade_journal.clone();
+        let confirmer = confirmer.clone();
+        let fills_feed = fills_feed.clone();
+        let perp_source = perp_source.clone();
+        let quoter = quoter.clone();
+        let circuit_breaker = circuit_breaker.clone();
+        let slot_tracker = slot_tracker.clone();
+        let metrics = metrics.clone();
+        let mut top_of_book_changed = top_of_book_changed.clone();
         async move {
-            let mut poll_interval = interval(MARKET_SCAN_INTERVAL);
+            let mut rescan = interval(RESCAN_INTERVAL);
             let mut throttle = interval(TRADING_COOLDOWN);
-            info!("Entering coordinator PERP->JUPITERSWAP loop (interval={:?}) ...", poll_interval.period());
+            info!("Entering coordinator PERP->JUPITERSWAP loop for {} (rescan={:?}) ...", trading_config.pair_name(), rescan.period());
             loop {
-                if matches!(calc_perp_position_allowance(mc.clone()).await, PerpAllowance::NoLong) {
-                    debug!("no perp long position allowance, skipping ...");
-                    poll_interval.tick().await;
-                    continue;
-                }
-
-                let orderbook_ask = last_ask_price.read().await;
-                debug!("orderbook(perp) best ask {:?}", *orderbook_ask);
-
-                let latest_swap_sell = drain_swap_sell_feed(&mut coo.sell_price_stream);
-                debug!("swap latest sell price {:?}", latest_swap_sell);
+                let event = tokio::select! {
+                    changed = sell_price_xread.changed() => {
+                        if changed.is_err() {
+                            warn!("{}: swap sell price feed closed", trading_config.pair_name());
+                            return;
+                        }
+                        // quotes replaced during a trade sequence are skipped
+                        let Some(quote) = sell_price_xread.borrow().clone() else {
+                            continue;
+                        };
+                        trace!("swap sell price {:?}", quote);
+                        PriceEvent::SwapQuote(quote.observed_at)
+                    }
+                    changed = top_of_book_changed.changed() => {
+                        if changed.is_err() {
+                            warn!("{}: orderbook feed closed", trading_config.pair_name());
+                            return;
+                        }
+                        let received_at = *top_of_book_changed.borrow();
+                        PriceEvent::TopOfBook(received_at)
+                    }
+                    _ = rescan.tick() => PriceEvent::Rescan,
+                };
 
-                if let (Some(perp_ask), Some(swap_sell)) = (*orderbook_ask, latest_swap_sell) {
-                    let profit = (swap_sell.price - perp_ask.price) / perp_ask.price;
-                    let should_trade = should_trade(profit);
-                    info!("{} swap-sell {:.2?} vs perp-ask {:.2?}, expected profit {:.2?}%",
-                        if should_trade { "*" } else { "." },
-                        swap_sell.price, perp_ask.price, 100.0 * profit);
+                let Some(swap_sell) = sell_price_xread.borrow().clone() else {
+                    continue;
+                };
 
-                    if should_trade && !dry_run {
-                        info!("profitable trade perp2swap detected, starting trade sequence ...");
-                        trade_sequence_perp2swap(mc.clone()).await;
-                        throttle.tick().await;
+                // buying the perp walks up the asks
+                let evaluation = match evaluate_opportunity(Direction::Perp2Swap, &trading_config, &profit_model, swap_sell.clone(), perp_source.as_ref()).await {
+                    Ok(evaluation) => {
+                        circuit_breaker.record_price_observation(&format!("{} perp", trading_config.pair_name()), evaluation.perp.observed_at);
+                        evaluation
                     }
+                    Err(err) => {
+                        debug!("{}: can't price perp leg, skipping: {}", trading_config.pair_name(), err);
+                        continue;
+                    }
+                };
+                let latency = event.latency(Instant::now());
+                if let Some(latency) = latency {
+                    metrics.set_gauge("decision_latency_ms", &[("pair", &trading_config.pair_name()), ("direction", "perp2swap")],
+                        latency.as_secs_f64() * 1000.0);
                 }
+                let estimate = evaluation.estimate;
+                info!("{} {}: swap-sell {:.2?} (size {}, impact {:.3}%) vs perp-ask vwap {:.2?}, expected net profit {:.2?}% ({}); {} latency {:?}",
+                    if evaluation.should_trade { "*" } else { "." }, trading_config.pair_name(),
+                    swap_sell.price, swap_sell.base_quantity_ui, 100.0 * swap_sell.price_impact_pct, evaluation.perp.price,
+                    100.0 * estimate.net_edge(), format_estimate(&estimate), event, latency);
 
-                poll_interval.tick().await;
+                if !evaluation.should_trade || dry_run {
+                    continue;
+                }
+                let allowance = calc_perp_position_allowance(mc.clone(), &trading_config).await;
+                circuit_breaker.record_rpc_result(allowance.is_ok());
+                match allowance {
+                    Ok(PerpAllowance::NoLong) => {
+                        debug!("{}: no perp long position allowance, skipping ...", trading_config.pair_name());
+                        continue;
+                    }
+                    Ok(_) => {}
+                    Err(err) => {
+                        warn!("{}: can't read the perp position, skipping ...: {:#}", trading_config.pair_name(), err);
+                        continue;
+                    }
+                }
+                if let Err(staleness) = check_price_freshness(&trading_config, &evaluation, slot_tracker.current_slot(), Instant::now()) {
+                    info!("{}: stale prices, skipping perp2swap trade: {}", trading_config.pair_name(), staleness);
+                    metrics.inc_counter("trades_refused_total", &[("pair", &trading_config.pair_name()), ("reason", staleness.label())]);
+                } else if let Some(_permit) = account_guard.try_begin_trade(mc.clone(), &trading_config, Direction::Perp2Swap, evaluation.perp.price).await {
+                    info!("profitable trade perp2swap detected on {}, starting trade sequence ...", trading_config.pair_name());
+                    trade_sequence_perp2swap(mc.clone(), trading_config.clone(), confirmer.clone(), fills_feed.clone(),
+                        quoter.clone(), swap_sell.swap_quote.clone(), &trade_journal, &circuit_breaker).await;
+                    throttle.tick().await;
+                }
             }
         }
     });
 
-    // make sure the fillter thread is up
+    if trading_config.inventory_band_base_ui.is_some() {
+        let manager = InventoryManager {
+            mango_client: mango_client.clone(),
+            trading_config: trading_config.clone(),
+            profit_model,
+            account_guard: account_guard.clone(),
+            swap_source: swap_source.clone(),
+            perp_source: perp_source.clone(),
+            highest_bid_price: coo.last_bid_price_shared.clone(),
+            lowest_ask_price: coo.last_ask_price_shared.clone(),
+            quoter: quoter.clone(),
+            confirmer: confirmer.clone(),
+            fills_feed: fills_feed.clone(),
+            metrics,
+            circuit_breaker: circuit_breaker.clone(),
+            dry_run,
+        };
+        supervisor.supervise_once(&format!("{} inventory manager", pair_name), manager.run());
+    }
+}
+
+async fn listen_orderbook(mango_client: Arc<MangoClientRef>, orderbook_source: OrderbookSource, trading_config: Arc<TradingConfig>,
+                          orderbook: Arc<RwLock<PerpOrderbook>>, last_bid_price: Arc<RwLock<Option<PriceInfo>>>,
+                          last_ask_price: Arc<RwLock<Option<PriceInfo>>>, top_of_book_events: TopOfBookEvents,
+                          recorder: Option<FeedRecorder>) {
+    // a restarted listener must not see prices left behind by the one that failed
+    *last_bid_price.write().await = None;
+    *last_ask_price.write().await = None;
+    orderbook.write().await.clear();
+
+    sleep(STARTUP_DELAY).await;
+    let market = &trading_config.market;
+    match orderbook_source {
+        OrderbookSource::MangoService => {
+            listen_perp_market_feed(market, orderbook, last_bid_price, last_ask_price, top_of_book_events, recorder).await;
+        }
+        OrderbookSource::Onchain => {
+            let market_index = mango_client.context.perp_market_indexes_by_name.get(&trading_config.perp_market_name).unwrap();
+            let perp_market = mango_client.context.perp_markets.get(market_index).unwrap().market;
+            let ws_url = mango_client.client.cluster.ws_url().to_string();
+            listen_onchain_perp_market(ws_url, perp_market, orderbook, last_bid_price, last_ask_price, top_of_book_events).await;
+        }
+    }
+    warn!("Orderbook WebSocket stream thread for market {} exited!", market);
+}
+
+async fn trade_sequence_swap2perp(mango_client: Arc<MangoClientRef>, trading_config: Arc<TradingConfig>,
+                                  confirmer: Arc<TransactionConfirmer>, fills_feed: FillsFeed,
+                                  quoter: Arc<JupiterQuoter>, swap_quote: Option<Arc<SwapQuote>>, journal: &TradeJournal,
+                                  circuit_breaker: &CircuitBreaker) {
+    // must be unique
+    let sequence_id = Utc::now().timestamp_micros() as u64;
+    info!("starting swap->perp trade sequence for {} ...", trading_config.pair_name());
+
+    let legs = Swap2PerpLegs {
+        mango_client,
+        trading_config: trading_config.clone(),
+        confirmer,
+        fills_feed,
+        quoter,
+        swap_quote,
+    };
+    let sequence = TradeSequence::new(sequence_id, trading_config.pair_name(), Direction::Swap2Perp);
+    let legs = BreakerLegs { legs: &legs, circuit_breaker };
+    let sequence = execute_trade_sequence(sequence, &legs, retry_budget(&trading_config), journal).await;
+    circuit_breaker.record_trade_finished(&sequence);
 
-    // buy_asset(mango_client.clone()).await;
-    // sell_asset(mango_client.clone()).await;
+    info!("trade sequence {} finished with outcome {:?}, perp cash flow {:.6}", sequence.id, sequence.outcome(), sequence.perp_cash_flow());
+}
 
-    // mango_client.mango_account().await.unwrap().
+async fn trade_sequence_perp2swap(mango_client: Arc<MangoClientRef>, trading_config: Arc<TradingConfig>,
+                                  confirmer: Arc<TransactionConfirmer>, fills_feed: FillsFeed,
+                                  quoter: Arc<JupiterQuoter>, swap_quote: Option<Arc<SwapQuote>>, journal: &TradeJournal,
+                                  circuit_breaker: &CircuitBreaker) {
+    // must be unique
+    let sequence_id = Utc::now().timestamp_micros() as u64;
+    info!("starting perp->swap trade sequence for {} ...", trading_config.pair_name());
+
+    let legs = Perp2SwapLegs {
+        mango_client,
+        trading_config: trading_config.clone(),
+        confirmer,
+        fills_feed,
+        quoter,
+        swap_quote,
+    };
+    let sequence = TradeSequence::new(sequence_id, trading_config.pair_name(), Direction::Perp2Swap);
+    let legs = BreakerLegs { legs: &legs, circuit_breaker };
+    let sequence = execute_trade_sequence(sequence, &legs, retry_budget(&trading_config), journal).await;
+    circuit_breaker.record_trade_finished(&sequence);
+
+    info!("trade sequence {} finished with outcome {:?}, perp cash flow {:.6}", sequence.id, sequence.outcome(), sequence.perp_cash_flow());
+}
 
-    tokio::join!(poll_buy_price, poll_sell_price, poll_orderbook);
+fn retry_budget(trading_config: &TradingConfig) -> RetryBudget {
+    RetryBudget {
+        max_attempts: trading_config.leg_retry_attempts,
+        delay: Duration::from_millis(trading_config.leg_retry_delay_ms),
+    }
+}
 
+// leg A: buy on jupiter, leg B: short perp
+struct Swap2PerpLegs {
+    mango_client: Arc<MangoClientRef>,
+    trading_config: Arc<TradingConfig>,
+    confirmer: Arc<TransactionConfirmer>,
+    fills_feed: FillsFeed,
+    quoter: Arc<JupiterQuoter>,
+    // route the decision was based on (swap venues which provide one)
+    swap_quote: Option<Arc<SwapQuote>>,
 }
 
-async fn trade_sequence_swap2perp(mango_client: Arc<MangoClientRef>) {
+#[async_trait]
+impl TradeLegs for Swap2PerpLegs {
+    async fn leg_a(&self) -> LegResult {
+        match &self.swap_quote {
+            Some(quote) => checked_swap_leg(&self.confirmer, &self.mango_client, &self.trading_config, QuoteSide::Buy, self.trading_config.base_qty_ui,
+                swap_quoted_route(self.mango_client.clone(), &self.quoter, &self.trading_config, quote)).await,
+            None => checked_swap_leg(&self.confirmer, &self.mango_client, &self.trading_config, QuoteSide::Buy, self.trading_config.base_qty_ui,
+                swap_buy_asset(self.mango_client.clone(), &self.quoter, &self.trading_config, self.trading_config.base_qty_ui)).await,
+        }
+    }
 
-    // must be unique
+    async fn leg_b(&self, base_quantity_ui: f64) -> LegResult {
+        // must be unique
+        let client_order_id = Utc::now().timestamp_micros() as u64;
+        filled_perp_leg(&self.confirmer, &self.fills_feed, &self.mango_client, &self.trading_config, QuoteSide::Sell, client_order_id, base_quantity_ui,
+            perp_ask_asset(self.mango_client.clone(), &self.trading_config, client_order_id, base_quantity_ui)).await
+    }
+
+    async fn unwind_a(&self, base_quantity_ui: f64) -> LegResult {
+        checked_swap_leg(&self.confirmer, &self.mango_client, &self.trading_config, QuoteSide::Sell, base_quantity_ui,
+            swap_sell_asset(self.mango_client.clone(), &self.quoter, &self.trading_config, base_quantity_ui)).await
+    }
+}
 
-    info!("starting swap->perp trade sequence ...");
+// leg A: long perp, leg B: sell on jupiter
+struct Perp2SwapLegs {
+    mango_client: Arc<MangoClientRef>,
+    trading_config: Arc<TradingConfig>,
+    confirmer: Arc<TransactionConfirmer>,
+    fills_feed: FillsFeed,
+    quoter: Arc<JupiterQuoter>,
+    // route the decision was based on (swap venues which provide one)
+    swap_quote: Option<Arc<SwapQuote>>,
+}
 
-    let swap_buy = swap_buy_asset(mango_client.clone(), BASE_QTY_UI).await;
-    // TODO check for confirmed state (ask max)
+#[async_trait]
+impl TradeLegs for Perp2SwapLegs {
+    async fn leg_a(&self) -> LegResult {
+        // must be unique
+        let client_order_id = Utc::now().timestamp_micros() as u64;
+        filled_perp_leg(&self.confirmer, &self.fills_feed, &self.mango_client, &self.trading_config, QuoteSide::Buy, client_order_id, self.trading_config.base_qty_ui,
+            perp_bid_asset(self.mango_client.clone(), &self.trading_config, client_order_id, self.trading_config.base_qty_ui)).await
+    }
 
-    if let Err(err) = swap_buy {
-        info!("Swap buy failed, aborting trade sequence: {}", err);
-        return;
+    async fn leg_b(&self, base_quantity_ui: f64) -> LegResult {
+        match &self.swap_quote {
+            // the quoted route only fits if leg A was filled completely
+            Some(quote) if base_quantity_ui >= self.trading_config.base_qty_ui => checked_swap_leg(&self.confirmer, &self.mango_client, &self.trading_config,
+                QuoteSide::Sell, base_quantity_ui, swap_quoted_route(self.mango_client.clone(), &self.quoter, &self.trading_config, quote)).await,
+            _ => checked_swap_leg(&self.confirmer, &self.mango_client, &self.trading_config, QuoteSide::Sell, base_quantity_ui,
+                swap_sell_asset(self.mango_client.clone(), &self.quoter, &self.trading_config, base_quantity_ui)).await,
+        }
     }
 
-    let async_ask = perp_ask_asset(mango_client.clone(), BASE_QTY_UI);
+    async fn unwind_a(&self, base_quantity_ui: f64) -> LegResult {
+        let client_order_id = Utc::now().timestamp_micros() as u64;
+        filled_perp_leg(&self.confirmer, &self.fills_feed, &self.mango_client, &self.trading_config, QuoteSide::Sell, client_order_id, base_quantity_ui,
+            perp_ask_asset(self.mango_client.clone(), &self.trading_config, client_order_id, base_quantity_ui)).await
+    }
+}
+
+// swap quotes for the trade size are polled in the background; the coordinator loops take the latest
+async fn poll_swap_quotes(swap_source: Arc<dyn PriceSource>, trading_config: Arc<TradingConfig>, side: QuoteSide,
+                          recorder: Option<FeedRecorder>, circuit_breaker: Arc<CircuitBreaker>, quotes: Arc<watch::Sender<Option<PriceQuote>>>) {
+    sleep(STARTUP_DELAY).await;
+    let mut interval = interval(Duration::from_millis(trading_config.swap_quote_interval_ms));
+    loop {
+        match swap_source.quote(side, trading_config.base_qty_ui).await {
+            Ok(quote) => {
+                debug!("{}: swap {} price ({}): {:?}", trading_config.pair_name(), side, swap_source.name(), quote);
+                if let Some(recorder) = &recorder {
+                    recorder.record_swap_quote(&trading_config.pair_name(), &quote);
+                }
+                circuit_breaker.record_price_observation(&format!("{} swap {}", trading_config.pair_name(), side), quote.observed_at);
+                if quotes.send(Some(quote)).is_err() {
+                    warn!("{}: swap {} quotes no longer consumed", trading_config.pair_name(), side);
+                    return;
+                }
+            }
+            Err(err) => warn!("{}: swap {} quote from {} failed: {:#}", trading_config.pair_name(), side, swap_source.name(), err),
+        }
 
-    let (sig_ask) = join!(async_ask);
+        for base_quantity_ui in &trading_config.quote_ladder_ui {
+            match swap_source.quote(side, *base_quantity_ui).await {
+                Ok(step) => debug!("{}: swap {} ladder {} -> {:.4} (impact {:.4}%)",
+                    trading_config.pair_name(), side, step.base_quantity_ui, step.price, 100.0 * step.price_impact_pct),
+                Err(err) => debug!("{}: swap {} ladder {} failed: {:#}", trading_config.pair_name(), side, base_quantity_ui, err),
+            }
+        }
 
-    info!("dispatched trading pair with signatures {} and {:?}", swap_buy.unwrap(), sig_ask);
+        interval.tick().await;
+    }
+}
 
-    info!("trade sequence completed.");
+// what woke a coordinator loop; feed events carry the time their message was received
+#[derive(Debug, Copy, Clone)]
+enum PriceEvent {
+    SwapQuote(Instant),
+    TopOfBook(Instant),
+    Rescan,
 }
 
-async fn trade_sequence_perp2swap(mango_client: Arc<MangoClientRef>) {
-    // must be unique
-    let client_order_id = Utc::now().timestamp_micros() as u64;
-    info!("starting perp->swap trade sequence (client_order_id {}) ...", client_order_id);
+impl PriceEvent {
+    // from feed message to decision
+    fn latency(&self, decided_at: Instant) -> Option<Duration> {
+        match self {
+            PriceEvent::SwapQuote(received_at) | PriceEvent::TopOfBook(received_at) =>
+                Some(decided_at.saturating_duration_since(*received_at)),
+            PriceEvent::Rescan => None,
+        }
+    }
+}
 
-    let async_bid = perp_bid_asset(mango_client.clone(), client_order_id, BASE_QTY_UI);
-    // TODO check for confirmed state (ask max)
+impl fmt::Display for PriceEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PriceEvent::SwapQuote(_) => write!(f, "swap quote"),
+            PriceEvent::TopOfBook(_) => write!(f, "top of book"),
+            PriceEvent::Rescan => write!(f, "rescan"),
+        }
+    }
+}
+
+// both legs of one direction priced for the trade size
+#[derive(Debug, Clone)]
+pub struct Evaluation {
+    pub swap: PriceQuote,
+    pub perp: PriceQuote,
+    pub estimate: ProfitEstimate,
+    pub should_trade: bool,
+}
+
+pub async fn evaluate_opportunity(direction: Direction, trading_config: &TradingConfig, profit_model: &ProfitModel,
+                                  swap_quote: PriceQuote, perp_source: &dyn PriceSource) -> anyhow::Result<Evaluation> {
+    let base_qty = trading_config.base_qty_ui;
+    let (perp, estimate) = match direction {
+        Direction::Swap2Perp => {
+            let perp = perp_source.quote(QuoteSide::Sell, base_qty).await?;
+            (perp, profit_model.swap2perp(base_qty, swap_quote.price, perp.price, swap_quote.fee_ui))
+        }
+        Direction::Perp2Swap => {
+            let perp = perp_source.quote(QuoteSide::Buy, base_qty).await?;
+            (perp, profit_model.perp2swap(base_qty, perp.price, swap_quote.price, swap_quote.fee_ui))
+        }
+    };
+    Ok(Evaluation {
+        swap: swap_quote,
+        perp,
+        estimate,
+        should_trade: should_trade(trading_config, &estimate),
+    })
+}
 
-    let swap_sell = swap_sell_asset(mango_client.clone(), BASE_QTY_UI).await;
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Staleness {
+    SwapAge(Duration),
+    PerpAge(Duration),
+    // orderbook slot behind the cluster slot
+    PerpSlotLag(u64),
+    // time between the observations of both legs
+    LegSkew(Duration),
+}
 
-    if let Err(err) = swap_sell {
-        info!("Swap sell failed, aborting trade sequence (!!! perp positions will remain open): {}", err);
-        return;
+impl Staleness {
+    pub fn label(&self) -> &'static str {
+        match self {
+            Staleness::SwapAge(_) => "stale_swap_price",
+            Staleness::PerpAge(_) => "stale_perp_price",
+            Staleness::PerpSlotLag(_) => "perp_slot_lag",
+            Staleness::LegSkew(_) => "leg_skew",
+        }
     }
+}
 
-    let (sig_bid) = join!(async_bid);
+impl fmt::Display for Staleness {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Staleness::SwapAge(age) => write!(f, "swap price is {:?} old", age),
+            Staleness::PerpAge(age) => write!(f, "perp price is {:?} old", age),
+            Staleness::PerpSlotLag(lag) => write!(f, "orderbook is {} slots behind", lag),
+            Staleness::LegSkew(skew) => write!(f, "legs were priced {:?} apart", skew),
+        }
+    }
+}
+
+// both legs must be recent and priced close together; the slot check is skipped until the cluster slot is known
+pub fn check_price_freshness(trading_config: &TradingConfig, evaluation: &Evaluation,
+                             current_slot: Option<u64>, now: Instant) -> Result<(), Staleness> {
+    let swap_age = now.saturating_duration_since(evaluation.swap.observed_at);
+    if swap_age > Duration::from_millis(trading_config.max_swap_price_age_ms) {
+        return Err(Staleness::SwapAge(swap_age));
+    }
+    let perp_age = now.saturating_duration_since(evaluation.perp.observed_at);
+    if perp_age > Duration::from_millis(trading_config.max_perp_price_age_ms) {
+        return Err(Staleness::PerpAge(perp_age));
+    }
+    if let (Some(current_slot), Some(perp_slot)) = (current_slot, evaluation.perp.slot) {
+        let lag = current_slot.saturating_sub(perp_slot);
+        if lag > trading_config.max_perp_slot_lag {
+            return Err(Staleness::PerpSlotLag(lag));
+        }
+    }
+    let skew = if evaluation.swap.observed_at > evaluation.perp.observed_at {
+        evaluation.swap.observed_at - evaluation.perp.observed_at
+    } else {
+        evaluation.perp.observed_at - evaluation.swap.observed_at
+    };
+    if skew > Duration::from_millis(trading_config.max_leg_skew_ms) {
+        return Err(Staleness::LegSkew(skew));
+    }
+    Ok(())
+}
 
-    info!("dispatched trading pair with signatures {:?} and {}", sig_bid, swap_sell.unwrap());
+async fn confirmed_leg(confirmer: &TransactionConfirmer, base_quantity_ui: f64,
+                       send_tx: impl Future<Output = anyhow::Result<Signature>>) -> anyhow::Result<LegReport> {
+    let signature = send_tx.await?;
+    let signature = confirmer.ensure_confirmed(signature, CommitmentConfig::confirmed()).await?;
+    Ok(LegReport {
+        signature,
+        requested_base_ui: base_quantity_ui,
+        executed_base_ui: 0.0,
+        perp_fill: None,
+        swap_base_delta: None,
+        swap_quote_delta: None,
+    })
+}
 
-    info!("trade sequence completed.");
+// swap leg is complete once the token position of the mango account moved in the direction and by the size of the trade;
+// a confirmed swap is never sent again - a smaller move in the right direction is reported as a partial fill
+pub(crate) async fn checked_swap_leg(confirmer: &TransactionConfirmer, mango_client: &MangoClientRef, trading_config: &TradingConfig,
+                                     side: QuoteSide, base_quantity_ui: f64,
+                                     send_tx: impl Future<Output = anyhow::Result<Signature>>) -> LegResult {
+    let (base_before, quote_before) = swap_positions_ui(mango_client, trading_config).await.map_err(LegError::NotExecuted)?;
+    let report = confirmed_leg(confirmer, base_quantity_ui, send_tx).await.map_err(LegError::NotExecuted)?;
+    let positions_after = match confirmer.confirmed_slot(&report.signature).await {
+        Ok(slot) => swap_positions_ui_at_slot(mango_client, confirmer, trading_config, slot).await,
+        Err(err) => Err(err),
+    };
+    let (base_after, quote_after) = match positions_after {
+        Ok(positions) => positions,
+        Err(reason) => return Err(LegError::Unverified { report, reason }),
+    };
+    let delta = base_after - base_before;
+    let report = LegReport {
+        swap_base_delta: Some(delta),
+        swap_quote_delta: Some(quote_after - quote_before),
+        ..report
+    };
+    let Err(reason) = check_swap_balance_delta(trading_config, side, base_quantity_ui, delta) else {
+        return Ok(LegReport {
+            executed_base_ui: base_quantity_ui,
+            ..report
+        });
+    };
+    let executed_base_ui = match side {
+        QuoteSide::Buy => delta,
+        QuoteSide::Sell => -delta,
+    };
+    if executed_base_ui > 0.0 && executed_base_ui < base_quantity_ui {
+        warn!("{}: swap {} confirmed with {} but only partially filled: {}", trading_config.pair_name(), side, report.signature, reason);
+        return Ok(LegReport {
+            executed_base_ui,
+            ..report
+        });
+    }
+    error!("{}: swap {} confirmed with {} but balance check failed: {}", trading_config.pair_name(), side, report.signature, reason);
+    Err(LegError::Unverified { report, reason })
 }
 
+// perp leg is complete once the fill shows up on the fills feed; a confirmed order is never sent again -
+// if the feed missed (part of) the fill, the executed quantity is taken from the perp position at the confirmed slot
+pub(crate) async fn filled_perp_leg(confirmer: &TransactionConfirmer, fills_feed: &FillsFeed, mango_client: &MangoClientRef,
+                                    trading_config: &TradingConfig, side: QuoteSide, client_order_id: u64, base_quantity_ui: f64,
+                                    send_tx: impl Future<Output = anyhow::Result<Signature>>) -> LegResult {
+    let position_before = perp_position_base_ui(mango_client, trading_config).await.map_err(LegError::NotExecuted)?;
+    let fills = fills_feed.subscribe();
+    let report = confirmed_leg(confirmer, base_quantity_ui, send_tx).await.map_err(LegError::NotExecuted)?;
+    let report = match fills.await_fill(&mango_client.mango_account_address, client_order_id,
+        base_quantity_ui, Duration::from_millis(trading_config.fill_timeout_ms)).await {
+        Ok(perp_fill) => LegReport {
+            executed_base_ui: perp_fill.quantity(),
+            perp_fill: Some(perp_fill),
+            ..report
+        },
+        Err(err) => {
+            warn!("{}: perp order {} confirmed with {} but no fill seen: {:#}", trading_config.pair_name(), client_order_id, report.signature, err);
+            report
+        }
+    };
+    if report.is_complete() {
+        return Ok(report);
+    }
 
-// drain feeds and get latest value
-fn drain_swap_buy_feed(feed: &mut UnboundedReceiver<SwapBuyPrice>) -> Option<SwapBuyPrice> {
-    let mut latest = None;
-    while let Ok(price) = feed.try_recv() {
-        trace!("drain swap buy price from feed {:?}", price);
-        latest = Some(price);
+    match perp_position_change(confirmer, mango_client, trading_config, side, position_before, &report).await {
+        Ok(executed_base_ui) => {
+            info!("{}: perp order {} executed {:.9} of {:.9} according to the perp position",
+                trading_config.pair_name(), client_order_id, executed_base_ui, base_quantity_ui);
+            Ok(LegReport {
+                executed_base_ui,
+                ..report
+            })
+        }
+        Err(reason) => Err(LegError::Unverified { report, reason }),
     }
-    latest
 }
 
-fn drain_swap_sell_feed(feed: &mut UnboundedReceiver<SwapSellPrice>) -> Option<SwapSellPrice> {
-    let mut latest = None;
-    while let Ok(price) = feed.try_recv() {
-        trace!("drain swap sell price from feed {:?}", price);
-        latest = Some(price);
+// base quantity traded by a confirmed perp order according to the position change
+async fn perp_position_change(confirmer: &TransactionConfirmer, mango_client: &MangoClientRef, trading_config: &TradingConfig,
+                              side: QuoteSide, position_before: f64, report: &LegReport) -> anyhow::Result<f64> {
+    let slot = confirmer.confirmed_slot(&report.signature).await?;
+    let position_after = perp_position_base_ui_at_slot(mango_client, confirmer, trading_config, slot).await?;
+    let delta = position_after - position_before;
+    let executed = match side {
+        QuoteSide::Buy => delta,
+        QuoteSide::Sell => -delta,
+    };
+    // anything else moving the position makes the change meaningless
+    if executed < 0.0 || executed > report.requested_base_ui / COMPLETE_FILL_RATIO {
+        bail!("perp position moved by {:+.9} at slot {} - expected {} of up to {:.9}", delta, slot, side, report.requested_base_ui);
     }
-    latest
+    Ok(executed)
 }
 
-fn should_trade(profit: f64) -> bool {
+
+fn format_estimate(estimate: &ProfitEstimate) -> String {
+    format!("gross {:.6}, perp fee {:.6}, slippage {:.6}, tx {:.6}, net {:.6} USDC; route fee {:.6} included in quote",
+        estimate.gross_pnl, estimate.perp_fee, estimate.slippage_cost, estimate.tx_cost, estimate.net_pnl, estimate.route_fee)
+}
+
+// trade only on net edge after fees and slippage
+pub fn should_trade(trading_config: &TradingConfig, estimate: &ProfitEstimate) -> bool {
     // 1 bps = 0.0001 = 0.01%
-    profit > PROFIT_THRESHOLD // 0.2%
+    estimate.net_pnl > 0.0 && estimate.net_edge() > trading_config.profit_threshold
 }
 
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+    use crate::coordinator::{check_price_freshness, evaluate_opportunity, Staleness};
+    use crate::profit_model::ProfitModel;
+    use crate::services::price_source::{MockPriceSource, PriceSource, QuoteSide};
+    use crate::services::trading_config::test_trading_config;
+    use crate::trade_sequence::Direction;
+
+    const MODEL: ProfitModel = ProfitModel {
+        perp_taker_fee: 0.0004,
+        swap_slippage_bps: 5,
+        tx_cost_ui: 0.001,
+    };
+
+    #[tokio::test]
+    async fn swap2perp_trades_against_perp_bid() {
+        let swap = MockPriceSource::with_prices(100.0, 99.0);
+        let perp = MockPriceSource::with_prices(102.0, 101.0);
+        let swap_buy = swap.quote(QuoteSide::Buy, 1.0).await.unwrap();
+
+        let evaluation = evaluate_opportunity(Direction::Swap2Perp, &test_trading_config(), &MODEL, swap_buy, &perp).await.unwrap();
+        assert_eq!(QuoteSide::Sell, evaluation.perp.side);
+        assert_eq!(101.0, evaluation.perp.price);
+        assert!(evaluation.should_trade);
+    }
+
+    #[tokio::test]
+    async fn perp2swap_skips_thin_edge() {
+        let swap = MockPriceSource::with_prices(100.1, 100.05);
+        let perp = MockPriceSource::with_prices(100.0, 99.9);
+        let swap_sell = swap.quote(QuoteSide::Sell, 1.0).await.unwrap();
+
+        let evaluation = evaluate_opportunity(Direction::Perp2Swap, &test_trading_config(), &MODEL, swap_sell, &perp).await.unwrap();
+        assert_eq!(QuoteSide::Buy, evaluation.perp.side);
+        assert!(evaluation.estimate.gross_pnl > 0.0);
+        assert!(!evaluation.should_trade);
+    }
+
+    #[tokio::test]
+    async fn unpriced_perp_leg_is_an_error() {
+        let swap = MockPriceSource::with_prices(100.0, 99.0);
+        let perp = MockPriceSource::default();
+        let swap_buy = swap.quote(QuoteSide::Buy, 1.0).await.unwrap();
+
+        assert!(evaluate_opportunity(Direction::Swap2Perp, &test_trading_config(), &MODEL, swap_buy, &perp).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn stale_or_skewed_prices_are_not_traded() {
+        let swap = MockPriceSource::with_prices(100.0, 99.0);
+        let perp = MockPriceSource::with_prices(102.0, 101.0);
+        let swap_buy = swap.quote(QuoteSide::Buy, 1.0).await.unwrap();
+        let mut evaluation = evaluate_opportunity(Direction::Swap2Perp, &test_trading_config(), &MODEL, swap_buy, &perp).await.unwrap();
+        let now = evaluation.swap.observed_at.max(evaluation.perp.observed_at);
+        evaluation.perp.slot = Some(1_000);
+
+        assert_eq!(Ok(()), check_price_freshness(&test_trading_config(), &evaluation, Some(1_025), now));
+        assert_eq!(Err(Staleness::PerpSlotLag(26)), check_price_freshness(&test_trading_config(), &evaluation, Some(1_026), now));
+        // slot lag is not checked before the first cluster slot arrives
+        assert_eq!(Ok(()), check_price_freshness(&test_trading_config(), &evaluation, None, now));
+
+        let later = now + Duration::from_secs(5);
+        assert!(matches!(check_price_freshness(&test_trading_config(), &evaluation, None, later), Err(Staleness::SwapAge(_))));
+
+        evaluation.swap.observed_at = later;
+        evaluation.perp.observed_at = later - Duration::from_millis(2_500);
+        assert_eq!(Err(Staleness::LegSkew(Duration::from_millis(2_500))), check_price_freshness(&test_trading_config(), &evaluation, None, later));
+    }
+}
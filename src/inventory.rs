@@ -0,0 +1,263 @@
+use std::sync::Arc;
+use std::time::Duration;
+use anyhow::bail;
+use chrono::Utc;
+use log::{debug, info, warn};
+use tokio::sync::RwLock;
+use tokio::time::{interval, sleep};
+use crate::MangoClientRef;
+use crate::account_guard::AccountGuard;
+use crate::circuit_breaker::CircuitBreaker;
+use crate::coordinator::{checked_swap_leg, filled_perp_leg};
+use crate::metrics::Metrics;
+use crate::profit_model::ProfitModel;
+use crate::services::fills_stream::FillsFeed;
+use crate::services::jupiter_quote::JupiterQuoter;
+use crate::services::orderbook_stream::PriceInfo;
+use crate::services::perp_orders::{perp_ask_asset, perp_bid_asset, perp_position_base_ui};
+use crate::services::price_source::{PriceQuote, PriceSource, QuoteSide};
+use crate::services::swap_orders::{swap_quoted_route, token_position_ui};
+use crate::services::trading_config::TradingConfig;
+use crate::services::transactions::TransactionConfirmer;
+
+// give the price feeds time to come up
+const STARTUP_DELAY: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum RebalanceVenue {
+    // market order on the perp orderbook
+    Perp,
+    // jupiter swap settled in the mango account
+    Swap,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct RebalancePlan {
+    pub venue: RebalanceVenue,
+    pub side: QuoteSide,
+    pub base_quantity_ui: f64,
+    pub price: f64,
+    // USDC vs. perp mid including fees and slippage tolerance
+    pub cost_ui: f64,
+}
+
+// positive = long the base token
+pub fn net_exposure_ui(trading_config: &TradingConfig, perp_base_ui: f64, spot_base_ui: f64) -> f64 {
+    perp_base_ui + spot_base_ui - trading_config.inventory_spot_baseline_ui
+}
+
+// cheapest order bringing the net exposure back to zero; None if the exposure is within the band
+pub fn plan_rebalance(trading_config: &TradingConfig, profit_model: &ProfitModel, net_exposure_ui: f64, mid_price: f64,
+                      perp_quote: Option<&PriceQuote>, swap_quote: Option<&PriceQuote>) -> anyhow::Result<Option<RebalancePlan>> {
+    let Some(band) = trading_config.inventory_band_base_ui else {
+        return Ok(None);
+    };
+    if net_exposure_ui.abs() <= band {
+        return Ok(None);
+    }
+
+    let perp_plan = perp_quote.map(|quote| RebalancePlan {
+        venue: RebalanceVenue::Perp,
+        side: quote.side,
+        base_quantity_ui: quote.base_quantity_ui,
+        price: quote.price,
+        cost_ui: quote.base_quantity_ui * ((quote.price - mid_price).abs() + quote.price * profit_model.perp_taker_fee),
+    });
+    let swap_plan = swap_quote.map(|quote| RebalancePlan {
+        venue: RebalanceVenue::Swap,
+        side: quote.side,
+        base_quantity_ui: quote.base_quantity_ui,
+        price: quote.price,
+        // route fees are part of the quoted price
+        cost_ui: quote.base_quantity_ui * ((quote.price - mid_price).abs()
+            + quote.price * profit_model.swap_slippage_bps as f64 / 10_000.0),
+    });
+
+    let cheapest = [perp_plan, swap_plan].into_iter()
+        .flatten()
+        .min_by(|a, b| a.cost_ui.total_cmp(&b.cost_ui));
+    let Some(cheapest) = cheapest else {
+        bail!("no venue could price a {:.9} base reduction", net_exposure_ui.abs());
+    };
+    if cheapest.cost_ui > trading_config.inventory_max_cost_ui {
+        bail!("cheapest reduction via {:?} costs {:.6} USDC (max {:.6})",
+            cheapest.venue, cheapest.cost_ui, trading_config.inventory_max_cost_ui);
+    }
+    Ok(Some(cheapest))
+}
+
+// reduces unhedged exposure left behind by failed legs of one pair
+pub struct InventoryManager {
+    pub mango_client: Arc<MangoClientRef>,
+    pub trading_config: Arc<TradingConfig>,
+    pub profit_model: ProfitModel,
+    pub account_guard: Arc<AccountGuard>,
+    pub swap_source: Arc<dyn PriceSource>,
+    pub perp_source: Arc<dyn PriceSource>,
+    pub highest_bid_price: Arc<RwLock<Option<PriceInfo>>>,
+    pub lowest_ask_price: Arc<RwLock<Option<PriceInfo>>>,
+    pub quoter: Arc<JupiterQuoter>,
+    pub confirmer: Arc<TransactionConfirmer>,
+    pub fills_feed: FillsFeed,
+    pub metrics: Arc<Metrics>,
+    pub circuit_breaker: Arc<CircuitBreaker>,
+    pub dry_run: bool,
+}
+
+impl InventoryManager {
+
+    pub async fn run(self) {
+        let pair_name = self.trading_config.pair_name();
+        sleep(STARTUP_DELAY).await;
+        let mut check_interval = interval(Duration::from_millis(self.trading_config.inventory_check_interval_ms));
+        info!("Entering inventory manager loop for {} (band={:?}, interval={:?}) ...",
+            pair_name, self.trading_config.inventory_band_base_ui, check_interval.period());
+        loop {
+            check_interval.tick().await;
+            if let Err(err) = self.check_inventory().await {
+                warn!("{}: inventory check failed: {}", pair_name, err);
+                self.metrics.inc_counter("inventory_rebalance_skipped_total", &[("pair", &pair_name)]);
+            }
+        }
+    }
+
+    async fn check_inventory(&self) -> anyhow::Result<()> {
+        let pair_name = self.trading_config.pair_name();
+        let Some(_permit) = self.account_guard.try_begin_rebalance() else {
+            debug!("{}: trading halted or trade sequence running, checking inventory later", pair_name);
+            return Ok(());
+        };
+
+        let positions = async {
+            let perp_base_ui = perp_position_base_ui(&self.mango_client, &self.trading_config).await?;
+            let spot_base_ui = token_position_ui(&self.mango_client, &self.trading_config).await?;
+            anyhow::Ok((perp_base_ui, spot_base_ui))
+        }.await;
+        self.circuit_breaker.record_rpc_result(positions.is_ok());
+        let (perp_base_ui, spot_base_ui) = positions?;
+        let net_exposure = net_exposure_ui(&self.trading_config, perp_base_ui, spot_base_ui);
+        self.metrics.set_gauge("inventory_net_exposure_base_ui", &[("pair", &pair_name)], net_exposure);
+        debug!("{}: perp position {:.9}, spot {:.9}, net exposure {:.9}", pair_name, perp_base_ui, spot_base_ui, net_exposure);
+
+        if net_exposure.abs() <= self.trading_config.inventory_band_base_ui.unwrap_or(f64::MAX) {
+            return Ok(());
+        }
+
+        let (Some(bid), Some(ask)) = (*self.highest_bid_price.read().await, *self.lowest_ask_price.read().await) else {
+            bail!("no perp mid price");
+        };
+        let mid_price = (bid.price + ask.price) / 2.0;
+
+        // sell when long, buy when short
+        let side = if net_exposure > 0.0 { QuoteSide::Sell } else { QuoteSide::Buy };
+        let quantity = net_exposure.abs();
+        let perp_quote = self.perp_source.quote(side, quantity).await
+            .map_err(|err| debug!("{}: no perp quote for rebalancing: {}", pair_name, err)).ok();
+        let swap_quote = self.swap_source.quote(side, quantity).await
+            .map_err(|err| debug!("{}: no swap quote for rebalancing: {}", pair_name, err)).ok();
+
+        let Some(plan) = plan_rebalance(&self.trading_config, &self.profit_model, net_exposure, mid_price,
+            perp_quote.as_ref(), swap_quote.as_ref())? else {
+            return Ok(());
+        };
+        info!("{}: net exposure {:.9} outside band, rebalancing with {:?} {} {:.9} @ {:.4} (cost {:.6} USDC){}",
+            pair_name, net_exposure, plan.venue, plan.side, plan.base_quantity_ui, plan.price, plan.cost_ui,
+            if self.dry_run { " - DRYRUN" } else { "" });
+        if self.dry_run {
+            return Ok(());
+        }
+
+        let report = match plan.venue {
+            RebalanceVenue::Perp => {
+                // must be unique
+                let client_order_id = Utc::now().timestamp_micros() as u64;
+                match plan.side {
+                    QuoteSide::Buy => filled_perp_leg(&self.confirmer, &self.fills_feed, &self.mango_client, &self.trading_config,
+                        QuoteSide::Buy, client_order_id, plan.base_quantity_ui,
+                        perp_bid_asset(self.mango_client.clone(), &self.trading_config, client_order_id, plan.base_quantity_ui)).await?,
+                    QuoteSide::Sell => filled_perp_leg(&self.confirmer, &self.fills_feed, &self.mango_client, &self.trading_config,
+                        QuoteSide::Sell, client_order_id, plan.base_quantity_ui,
+                        perp_ask_asset(self.mango_client.clone(), &self.trading_config, client_order_id, plan.base_quantity_ui)).await?,
+                }
+            }
+            RebalanceVenue::Swap => {
+                let Some(route) = swap_quote.and_then(|quote| quote.swap_quote) else {
+                    bail!("swap quote without route");
+                };
+                checked_swap_leg(&self.confirmer, &self.mango_client, &self.trading_config, plan.side, plan.base_quantity_ui,
+                    swap_quoted_route(self.mango_client.clone(), &self.quoter, &self.trading_config, &route)).await?
+            }
+        };
+        info!("{}: rebalanced with {}", pair_name, report);
+        self.metrics.inc_counter("inventory_rebalances_total", &[("pair", &pair_name), ("venue", match plan.venue {
+            RebalanceVenue::Perp => "perp",
+            RebalanceVenue::Swap => "swap",
+        })]);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Instant;
+    use crate::services::trading_config::test_trading_config;
+    use super::*;
+
+    fn trading_config() -> TradingConfig {
+        TradingConfig {
+            inventory_band_base_ui: Some(0.5),
+            inventory_spot_baseline_ui: 10.0,
+            inventory_max_cost_ui: 0.5,
+            ..test_trading_config()
+        }
+    }
+
+    const MODEL: ProfitModel = ProfitModel {
+        perp_taker_fee: 0.0004,
+        swap_slippage_bps: 5,
+        tx_cost_ui: 0.0,
+    };
+
+    fn quote(side: QuoteSide, price: f64) -> PriceQuote {
+        PriceQuote {
+            side,
+            price,
+            base_quantity_ui: 1.0,
+            price_impact_pct: 0.0,
+            fee_ui: 0.0,
+            observed_at: Instant::now(),
+            slot: None,
+            swap_quote: None,
+        }
+    }
+
+    #[test]
+    fn spot_baseline_is_not_exposure() {
+        let trading_config = trading_config();
+        assert_eq!(0.0, net_exposure_ui(&trading_config, -1.0, 11.0));
+        assert_eq!(1.0, net_exposure_ui(&trading_config, 0.0, 11.0));
+    }
+
+    #[test]
+    fn no_rebalance_within_band() {
+        let plan = plan_rebalance(&trading_config(), &MODEL, 0.4, 100.0,
+            Some(&quote(QuoteSide::Sell, 99.9)), None).unwrap();
+        assert!(plan.is_none());
+    }
+
+    #[test]
+    fn rebalance_with_cheapest_venue() {
+        // perp: 0.1 + 0.04 fee, swap: 0.05 + 0.05 slippage
+        let plan = plan_rebalance(&trading_config(), &MODEL, 1.0, 100.0,
+            Some(&quote(QuoteSide::Sell, 99.9)), Some(&quote(QuoteSide::Sell, 99.95))).unwrap().unwrap();
+        assert_eq!(RebalanceVenue::Swap, plan.venue);
+        assert_eq!(QuoteSide::Sell, plan.side);
+        assert!((plan.cost_ui - 0.099975).abs() < 1e-9);
+    }
+
+    #[test]
+    fn rebalance_over_budget_is_refused() {
+        assert!(plan_rebalance(&trading_config(), &MODEL, -1.0, 100.0,
+            Some(&quote(QuoteSide::Buy, 101.0)), None).is_err());
+    }
+}
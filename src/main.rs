@@ -1,6 +1,15 @@
 mod services;
 mod coordinator;
 mod numerics;
+mod account_guard;
+mod trade_sequence;
+mod profit_model;
+mod backtest;
+mod metrics;
+mod inventory;
+mod circuit_breaker;
+mod supervisor;
+mod shutdown;
 
 use std::future::Future;
 use std::ops::Deref;
@@ -15,6 +24,7 @@ use std::thread::sleep;
 use std::time::{Duration, Instant};
 use chrono::Utc;
 use futures::TryFutureExt;
+use itertools::Itertools;
 // use jsonrpc_core_client::transports::ws;
 // use jsonrpc_core_client::TypedSubscriptionStream;
 use solana_client::rpc_config::RpcSignatureSubscribeConfig;
@@ -31,9 +41,18 @@ use fixed::types::I80F48;
 use mango_v4::state::{PerpMarket, PerpMarketIndex, PlaceOrderType, QUOTE_DECIMALS, Side};
 use crate::numerics::{native_amount, native_amount_to_lot, quote_amount_to_lot};
 use crate::services::blockhash::start_blockhash_service;
+use crate::services::slot_tracker::start_slot_tracker;
+use crate::circuit_breaker::{listen_for_kill_switch_signals, CircuitBreaker};
+use crate::metrics::Metrics;
+use crate::services::feed_recorder::FeedRecorder;
 use crate::services::perp_orders::{perp_bid_asset, perp_ask_asset, calc_perp_position_allowance};
 use crate::services::swap_orders::swap_buy_asset;
-use crate::services::{trading_config, transactions};
+use crate::services::transactions::TransactionConfirmer;
+use crate::services::trading_config::BotConfig;
+use crate::supervisor::Supervisor;
+use crate::account_guard::AccountGuard;
+use crate::shutdown::{shutdown_signal, Shutdown};
+use crate::trade_sequence::TradeJournal;
 
 use solana_client::rpc_response::SlotUpdate;
 // use jsonrpc_core::futures::StreamExt;
@@ -51,6 +70,22 @@ use websocket_tungstenite_retry::websocket_stable::StableWebSocket;
 #[clap()]
 struct Cli {
 
+    #[clap(subcommand)]
+    command: Command,
+
+}
+
+#[derive(Subcommand, Debug, Clone)]
+enum Command {
+    // trade live (or dry run) against mainnet
+    Run(RunArgs),
+    // replay a recorded feed file through the trade decision logic
+    Backtest(BacktestArgs),
+}
+
+#[derive(Args, Debug, Clone)]
+struct RunArgs {
+
     #[clap(short, long)]
     dry_run: bool,
 
@@ -66,6 +101,35 @@ struct Cli {
     #[clap(short, long, env)]
     owner: String,
 
+    // path to toml file with trading config, e.g. config/sol-perp.toml
+    #[clap(short, long, env)]
+    config: String,
+
+    // record orderbook messages and swap quotes to json lines files in this directory (input for backtest)
+    #[clap(long, env)]
+    record_dir: Option<String>,
+
+    // serve metrics in the prometheus text format on this address, e.g. 127.0.0.1:9100
+    #[clap(long, env)]
+    metrics_addr: Option<String>,
+
+}
+
+#[derive(Args, Debug, Clone)]
+struct BacktestArgs {
+
+    // path to toml file with trading config, e.g. config/sol-perp.toml
+    #[clap(short, long, env)]
+    config: String,
+
+    // recorded feed file (json lines)
+    #[clap(short, long)]
+    input: String,
+
+    // perp taker fee rate (not read from the mango group in backtests)
+    #[clap(long, default_value = "0.0004")]
+    perp_taker_fee: f64,
+
 }
 
 
@@ -80,6 +144,18 @@ async fn main() -> Result<(), anyhow::Error> {
 
     let cli = Cli::parse_from(std::env::args_os());
 
+    match cli.command {
+        Command::Run(run_args) => run_bot(run_args).await,
+        Command::Backtest(backtest_args) => {
+            let bot_config = BotConfig::load_from_file(&backtest_args.config)?;
+            backtest::run_backtest(&bot_config, &backtest_args.input, backtest_args.perp_taker_fee).await
+        }
+    }
+}
+
+async fn run_bot(cli: RunArgs) -> anyhow::Result<()> {
+    let bot_config = Arc::new(BotConfig::load_from_file(&cli.config)?);
+
     let dry_run = cli.dry_run;
     let rpc_url = cli.rpc_url;
     let ws_url = rpc_url.replace("https", "wss").replace("http", "ws");
@@ -89,8 +165,8 @@ async fn main() -> Result<(), anyhow::Error> {
 
     let cluster = Cluster::Custom(rpc_url.clone(), ws_url.clone());
 
-    info!("Starting arbi-bot{} with RPC {} trading '{}' vs '{}' ...", if dry_run { "(DRYRUN)" } else { "" },
-        rpc_url, trading_config::PERP_MARKET_NAME, trading_config::TOKEN_NAME);
+    info!("Starting arbi-bot{} with RPC {} trading {} ...", if dry_run { "(DRYRUN)" } else { "" },
+        rpc_url, bot_config.pairs.iter().map(|pair| format!("'{}' vs '{}'", pair.perp_market_name, pair.token_name)).join(", "));
 
     let mango_client = Arc::new(
         new_mango_client(
@@ -101,17 +177,59 @@ async fn main() -> Result<(), anyhow::Error> {
                 owner.clone(),
                 Some(Duration::from_secs(12)),
                 TransactionBuilderConfig {
-                    prioritization_micro_lamports: Some(1),
+                    prioritization_micro_lamports: Some(bot_config.compute_unit_price_micro_lamports),
                 },
             ),
             cli.mango_account,
             owner.clone(),
         ).await?);
 
-    let coordinator_thread = tokio::spawn(coordinator::run_coordinator_service(mango_client.clone(), dry_run));
-    coordinator_thread.await?;
+    bot_config.validate_against_group(&mango_client.context)?;
+
+    let metrics = Arc::new(Metrics::default());
+    if let Some(metrics_addr) = &cli.metrics_addr {
+        metrics.clone().serve(metrics_addr).await?;
+    }
+    let supervisor = Supervisor::new(metrics.clone());
+
+    let confirmer = Arc::new(TransactionConfirmer::new(rpc_url.clone(), ws_url.clone(), &supervisor).await);
+    let slot_tracker = start_slot_tracker(rpc_url.clone(), &supervisor);
+
+    let recorder = cli.record_dir.as_deref().map(FeedRecorder::start).transpose()?;
+
+    let circuit_breaker = Arc::new(CircuitBreaker::new(bot_config.circuit_breaker.clone(), metrics.clone()));
+    tokio::spawn(listen_for_kill_switch_signals(circuit_breaker.clone()));
+
+    let account_guard = Arc::new(AccountGuard::new(bot_config.clone(), metrics.clone(), circuit_breaker.clone()));
+    let trade_journal = Arc::new(TradeJournal::default());
 
-    Ok(())
+    coordinator::start_coordinator_service(mango_client.clone(), bot_config.clone(), confirmer.clone(), recorder.clone(),
+        metrics.clone(), circuit_breaker, slot_tracker, account_guard.clone(), trade_journal.clone(), &supervisor, dry_run);
+
+    // runs until a signal or until a task which can't be restarted ends; exit with an error then
+    let failure = tokio::select! {
+        signal = shutdown_signal() => {
+            info!("{} received, shutting down ...", signal);
+            None
+        }
+        failure = supervisor.escalated() => Some(failure),
+    };
+
+    Shutdown {
+        mango_client,
+        bot_config,
+        account_guard,
+        trade_journal,
+        confirmer,
+        recorder,
+        metrics,
+        dry_run,
+    }.run().await;
+
+    match failure {
+        Some(failure) => anyhow::bail!("shut down after failure: {}", failure),
+        None => Ok(()),
+    }
 }
 
 pub struct MangoClientRef {
@@ -0,0 +1,114 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use anyhow::Context;
+use log::{info, warn};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum MetricKind {
+    Counter,
+    Gauge,
+}
+
+// name -> (kind, rendered labels -> value)
+type MetricFamilies = BTreeMap<String, (MetricKind, BTreeMap<String, f64>)>;
+
+// counters and gauges exported in the prometheus text format
+#[derive(Default)]
+pub struct Metrics {
+    families: Mutex<MetricFamilies>,
+}
+
+impl Metrics {
+
+    pub fn set_gauge(&self, name: &str, labels: &[(&str, &str)], value: f64) {
+        let mut families = self.families.lock().unwrap();
+        let (_, series) = families.entry(name.to_string()).or_insert_with(|| (MetricKind::Gauge, BTreeMap::new()));
+        series.insert(render_labels(labels), value);
+    }
+
+    pub fn inc_counter(&self, name: &str, labels: &[(&str, &str)]) {
+        let mut families = self.families.lock().unwrap();
+        let (_, series) = families.entry(name.to_string()).or_insert_with(|| (MetricKind::Counter, BTreeMap::new()));
+        *series.entry(render_labels(labels)).or_insert(0.0) += 1.0;
+    }
+
+    pub fn render(&self) -> String {
+        let families = self.families.lock().unwrap();
+        let mut out = String::new();
+        for (name, (kind, series)) in families.iter() {
+            let kind = match kind {
+                MetricKind::Counter => "counter",
+                MetricKind::Gauge => "gauge",
+            };
+            writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+            for (labels, value) in series {
+                writeln!(out, "{}{} {}", name, labels, value).unwrap();
+            }
+        }
+        out
+    }
+
+    // answers every request on the address with the current metrics, e.g. for GET /metrics
+    pub async fn serve(self: std::sync::Arc<Self>, addr: &str) -> anyhow::Result<()> {
+        let listener = TcpListener::bind(addr).await
+            .with_context(|| format!("Can't bind metrics listener to <{}>", addr))?;
+        info!("Serving metrics on {}", addr);
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = match listener.accept().await {
+                    Ok(conn) => conn,
+                    Err(err) => {
+                        warn!("metrics listener failed to accept connection: {}", err);
+                        continue;
+                    }
+                };
+                let body = self.render();
+                tokio::spawn(async move {
+                    // request is not inspected
+                    let mut buf = [0u8; 1024];
+                    let _ = stream.read(&mut buf).await;
+                    let response = format!("HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        body.len(), body);
+                    if let Err(err) = stream.write_all(response.as_bytes()).await {
+                        warn!("failed to write metrics response: {}", err);
+                    }
+                });
+            }
+        });
+        Ok(())
+    }
+}
+
+// e.g. {pair="SOL-PERP/SOL",reason="init_health"}
+fn render_labels(labels: &[(&str, &str)]) -> String {
+    if labels.is_empty() {
+        return String::new();
+    }
+    let labels = labels.iter()
+        .map(|(key, value)| format!("{}=\"{}\"", key, value.replace('\\', "\\\\").replace('"', "\\\"")))
+        .collect::<Vec<_>>()
+        .join(",");
+    format!("{{{}}}", labels)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn render_counters_and_gauges() {
+        let metrics = Metrics::default();
+        metrics.inc_counter("trades_refused_total", &[("pair", "SOL-PERP/SOL"), ("reason", "init_health")]);
+        metrics.inc_counter("trades_refused_total", &[("pair", "SOL-PERP/SOL"), ("reason", "init_health")]);
+        metrics.set_gauge("account_init_health_ui", &[], 12.5);
+
+        assert_eq!("# TYPE account_init_health_ui gauge\n\
+                    account_init_health_ui 12.5\n\
+                    # TYPE trades_refused_total counter\n\
+                    trades_refused_total{pair=\"SOL-PERP/SOL\",reason=\"init_health\"} 2\n",
+            metrics.render());
+    }
+}
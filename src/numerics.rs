@@ -63,8 +63,19 @@ pub fn quantity_to_lot(lot_conf: ConversionConf, amount: f64) -> I80F48 {
         / I80F48::from_num(lot_conf.base_lot_size)
 }
 
+// base lots to base ui, e.g. 1 lot of ETH-PERP = 0.0001 ETH
+pub fn lot_to_quantity_ui(lot_conf: ConversionConf, base_lots: i64) -> f64 {
+    base_lots as f64 * lot_conf.base_lot_size as f64 / 10f64.powi(lot_conf.base_decimals as i32)
+}
+
+// orderbook price in quote lots per base lot to ui price (USDC per base ui)
+pub fn lot_price_to_ui(lot_conf: ConversionConf, price_lots: i64) -> f64 {
+    let native_price = price_lots as f64 * lot_conf.quote_lot_size as f64 / lot_conf.base_lot_size as f64;
+    native_price * 10f64.powi(lot_conf.base_decimals as i32 - QUOTE_DECIMALS as i32)
+}
+
 mod test {
-    use crate::numerics::{ConversionConf, native_amount, native_amount_to_lot, quantity_to_lot, quote_amount_to_lot};
+    use crate::numerics::{ConversionConf, lot_price_to_ui, lot_to_quantity_ui, native_amount, native_amount_to_lot, quantity_to_lot, quote_amount_to_lot};
 
     #[test]
     fn convert_quantity_eth_perp() {
@@ -84,5 +95,18 @@ mod test {
         // quantity_to_lot()
 
     }
+
+    #[test]
+    fn convert_lots_to_ui_eth_perp() {
+        let sample = ConversionConf {
+            base_decimals: 6,
+            base_lot_size: 100,
+            quote_lot_size: 10,
+        };
+
+        assert_eq!(0.0001, lot_to_quantity_ui(sample, 1));
+        // 1900 USDC per ETH = 1900 native quote per native base = 19_000 quote lots (10) per base lot (100)
+        assert!((1900.0 - lot_price_to_ui(sample, 19_000)).abs() < 1e-9);
+    }
 }
 
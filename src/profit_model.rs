@@ -0,0 +1,125 @@
+use mango_v4_client::MangoGroupContext;
+use crate::services::trading_config::{BotConfig, TradingConfig};
+
+// solana base fee per signature
+const LAMPORTS_PER_SIGNATURE: u64 = 5000;
+const LAMPORTS_PER_SOL: f64 = 1e9;
+// one transaction per leg
+const TRANSACTIONS_PER_TRADE: u64 = 2;
+
+// expected net PnL of one arbitrage opportunity (both legs) in quote units (USDC)
+#[derive(Debug, Copy, Clone)]
+pub struct ProfitModel {
+    // rate, e.g. 0.0004 = 4 bps
+    pub perp_taker_fee: f64,
+    pub swap_slippage_bps: u64,
+    // base and priority fees of all transactions of one trade sequence
+    pub tx_cost_ui: f64,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct ProfitEstimate {
+    pub notional: f64,
+    pub gross_pnl: f64,
+    pub perp_fee: f64,
+    // informational: jupiter quotes are net of route fees, so they are already part of gross_pnl
+    pub route_fee: f64,
+    // worst case with the configured slippage tolerance
+    pub slippage_cost: f64,
+    pub tx_cost: f64,
+    pub net_pnl: f64,
+}
+
+impl ProfitEstimate {
+    // net PnL relative to the traded notional - compare against profit_threshold
+    pub fn net_edge(&self) -> f64 {
+        self.net_pnl / self.notional
+    }
+}
+
+impl ProfitModel {
+
+    pub fn new(context: &MangoGroupContext, trading_config: &TradingConfig, bot_config: &BotConfig) -> Self {
+        let market_index = context.perp_market_indexes_by_name.get(&trading_config.perp_market_name).unwrap();
+        let perp_market = &context.perp_markets.get(market_index).unwrap().market;
+
+        Self::from_fees(perp_market.taker_fee.to_num::<f64>(), trading_config, bot_config)
+    }
+
+    // without access to the mango group, e.g. for backtests
+    pub fn from_fees(perp_taker_fee: f64, trading_config: &TradingConfig, bot_config: &BotConfig) -> Self {
+        let priority_fee_lamports = bot_config.compute_unit_price_micro_lamports * bot_config.estimated_compute_units_per_tx / 1_000_000;
+        let tx_cost_lamports = TRANSACTIONS_PER_TRADE * (LAMPORTS_PER_SIGNATURE + priority_fee_lamports);
+
+        ProfitModel {
+            perp_taker_fee,
+            swap_slippage_bps: trading_config.swap_slippage_bps,
+            tx_cost_ui: tx_cost_lamports as f64 / LAMPORTS_PER_SOL * bot_config.sol_price_ui_estimate,
+        }
+    }
+
+    // buy on jupiter, short on perp
+    pub fn swap2perp(&self, base_quantity: f64, swap_buy_price: f64, perp_bid_vwap: f64, route_fee: f64) -> ProfitEstimate {
+        self.estimate(base_quantity, swap_buy_price, perp_bid_vwap, perp_bid_vwap, swap_buy_price, route_fee)
+    }
+
+    // long on perp, sell on jupiter
+    pub fn perp2swap(&self, base_quantity: f64, perp_ask_vwap: f64, swap_sell_price: f64, route_fee: f64) -> ProfitEstimate {
+        self.estimate(base_quantity, perp_ask_vwap, swap_sell_price, perp_ask_vwap, swap_sell_price, route_fee)
+    }
+
+    fn estimate(&self, base_quantity: f64, buy_price: f64, sell_price: f64,
+                perp_price: f64, swap_price: f64, route_fee: f64) -> ProfitEstimate {
+        let notional = base_quantity * buy_price;
+        let gross_pnl = base_quantity * (sell_price - buy_price);
+        let perp_fee = base_quantity * perp_price * self.perp_taker_fee;
+        let slippage_cost = base_quantity * swap_price * self.swap_slippage_bps as f64 / 10_000.0;
+        let net_pnl = gross_pnl - perp_fee - slippage_cost - self.tx_cost_ui;
+        ProfitEstimate {
+            notional,
+            gross_pnl,
+            perp_fee,
+            route_fee,
+            slippage_cost,
+            tx_cost: self.tx_cost_ui,
+            net_pnl,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::profit_model::ProfitModel;
+
+    const MODEL: ProfitModel = ProfitModel {
+        perp_taker_fee: 0.0004,
+        swap_slippage_bps: 5,
+        tx_cost_ui: 0.001,
+    };
+
+    fn assert_close(expected: f64, actual: f64) {
+        assert!((expected - actual).abs() < 1e-9, "expected {} but was {}", expected, actual);
+    }
+
+    #[test]
+    fn swap2perp_net_pnl() {
+        // buy 1.0 @ 100 on jupiter, sell 1.0 @ 101 on perp
+        let estimate = MODEL.swap2perp(1.0, 100.0, 101.0, 0.25);
+        assert_close(100.0, estimate.notional);
+        assert_close(1.0, estimate.gross_pnl);
+        assert_close(0.0404, estimate.perp_fee);
+        assert_close(0.05, estimate.slippage_cost);
+        assert_close(1.0 - 0.0404 - 0.05 - 0.001, estimate.net_pnl);
+        // route fee is informational only
+        assert_close(0.25, estimate.route_fee);
+    }
+
+    #[test]
+    fn perp2swap_fees_turn_edge_negative() {
+        // buy 1.0 @ 100 on perp, sell 1.0 @ 100.05 on jupiter
+        let estimate = MODEL.perp2swap(1.0, 100.0, 100.05, 0.0);
+        assert!(estimate.gross_pnl > 0.0);
+        assert!(estimate.net_pnl < 0.0);
+        assert!(estimate.net_edge() < 0.0);
+    }
+}
@@ -1,121 +1,338 @@
-use std::str::FromStr;
+use std::sync::Arc;
 use std::time::Instant;
-use anchor_lang::prelude::Pubkey;
 
-use anyhow::Context;
-use mango_v4_client::jupiter::v4::{JupiterV4, QueryRoute};
-use mango_v4_client::JupiterSwapMode;
-use serde::{Deserialize, Serialize};
-use crate::services::trading_config::{BASE_DECIMALS, MINT_ADDRESS_INPUT, MINT_ADDRESS_OUTPUT};
+use crate::numerics::native_amount2;
+use crate::services::jupiter_quote::{JupiterQuoter, SwapMode, SwapQuote};
+use crate::services::price_source::QuoteSide;
+use crate::services::trading_config::TradingConfig;
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Clone)]
 pub struct SwapBuyPrice {
     // ETH in USD - e.g 1900
     pub price: f64,
+    // size the price was quoted for, e.g. 0.01 SOL
+    pub base_quantity_ui: f64,
+    // native USDC paid
+    pub in_amount: u64,
+    // native base token received
+    pub out_amount: u64,
+    // as reported by the router, e.g. 0.0026 = 0.26%
+    pub price_impact_pct: f64,
+    // LP and platform fees of the route in USDC - already reflected in the price
+    pub route_fee_ui: f64,
     pub approx_timestamp: Instant,
+    // route to execute the swap with
+    pub quote: Arc<SwapQuote>,
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Clone)]
 pub struct SwapSellPrice {
     // ETH in USD - e.g 1900
     pub price: f64,
+    // size the price was quoted for, e.g. 0.01 SOL
+    pub base_quantity_ui: f64,
+    // native base token paid
+    pub in_amount: u64,
+    // native USDC received
+    pub out_amount: u64,
+    // as reported by the router, e.g. 0.0026 = 0.26%
+    pub price_impact_pct: f64,
+    // LP and platform fees of the route in USDC - already reflected in the price
+    pub route_fee_ui: f64,
     pub approx_timestamp: Instant,
+    // route to execute the swap with
+    pub quote: Arc<SwapQuote>,
 }
 
+#[derive(Debug, Clone)]
+struct QuotedPrice {
+    price: f64,
+    price_impact_pct: f64,
+    route_fee_ui: f64,
+    quote: SwapQuote,
+}
+
+// sum up fees of all route steps converted to USDC
+fn route_fee_ui(quote: &SwapQuote, trading_config: &TradingConfig, price: f64) -> f64 {
+    let quote_mint = trading_config.mint_address_input.as_str();
+    let base_mint = trading_config.mint_address_output.as_str();
+    quote.fees.iter()
+        .map(|(mint, amount)| {
+            if mint == quote_mint {
+                *amount as f64 / 1e6
+            } else if mint == base_mint {
+                *amount as f64 / 10f64.powi(trading_config.base_decimals as i32) * price
+            } else {
+                // intermediate tokens are not used (only direct routes)
+                0.0
+            }
+        })
+        .sum()
+}
+
+// route for buying (USDC->token) or selling (token->USDC) the given base quantity in the configured swap mode;
+// if the mode fixes the USDC side, the USDC amount is taken from a quote fixing the base side first
+pub async fn quote_swap_leg(quoter: &JupiterQuoter, trading_config: &TradingConfig, side: QuoteSide, base_quantity_ui: f64) -> anyhow::Result<SwapQuote> {
+    let slippage_bps = trading_config.swap_slippage_bps;
+    let base_amount = native_amount2(trading_config.base_decimals as u32, base_quantity_ui);
+    let quote_mint = trading_config.mint_input();
+    let base_mint = trading_config.mint_output();
+
+    match (side, side_swap_mode(trading_config, side)) {
+        (QuoteSide::Buy, SwapMode::ExactOut) =>
+            quoter.quote(quote_mint, base_mint, base_amount, SwapMode::ExactOut, slippage_bps, true).await,
+        (QuoteSide::Buy, SwapMode::ExactIn) => {
+            let reference = quoter.quote(quote_mint, base_mint, base_amount, SwapMode::ExactOut, slippage_bps, true).await?;
+            quoter.quote(quote_mint, base_mint, reference.in_amount, SwapMode::ExactIn, slippage_bps, true).await
+        }
+        (QuoteSide::Sell, SwapMode::ExactIn) =>
+            quoter.quote(base_mint, quote_mint, base_amount, SwapMode::ExactIn, slippage_bps, true).await,
+        (QuoteSide::Sell, SwapMode::ExactOut) => {
+            let reference = quoter.quote(base_mint, quote_mint, base_amount, SwapMode::ExactIn, slippage_bps, true).await?;
+            quoter.quote(base_mint, quote_mint, reference.out_amount, SwapMode::ExactOut, slippage_bps, true).await
+        }
+    }
+}
+
+fn side_swap_mode(trading_config: &TradingConfig, side: QuoteSide) -> SwapMode {
+    match side {
+        QuoteSide::Buy => trading_config.swap_buy_mode,
+        QuoteSide::Sell => trading_config.swap_sell_mode,
+    }
+}
+
+// sell the base token for USDC
 // e.g. 0.18USD for 0.0001 ETH
 // max(sell)
-async fn calc_price_exactin<'a>(jupiter: &JupiterV4<'a>) -> f64 {
+async fn calc_sell_price(quoter: &JupiterQuoter, trading_config: &TradingConfig, base_quantity_ui: f64) -> anyhow::Result<QuotedPrice> {
     let usd_decimals = 6;
-    let decimals = BASE_DECIMALS - usd_decimals;
+    let decimals = trading_config.base_decimals - usd_decimals;
     let multiplier = 10f64.powf(decimals.into()) as f64;
 
-    const slippage_bps: u64 = 5;
-    const amount: u64 = 100000;
-
-    let route: QueryRoute = jupiter
-        .quote(
-            Pubkey::from_str(MINT_ADDRESS_INPUT).unwrap(),
-            Pubkey::from_str(MINT_ADDRESS_OUTPUT).unwrap(),
-            amount, slippage_bps, JupiterSwapMode::ExactOut, true)
-        .await.unwrap();
-
-    let price = route.in_amount.parse::<u64>().unwrap() as f64 / route.out_amount.parse::<u64>().unwrap() as f64 * multiplier;
+    let quote = quote_swap_leg(quoter, trading_config, QuoteSide::Sell, base_quantity_ui).await?;
 
-    price
+    // USDC out per token in
+    let price = quote.out_amount as f64 / quote.in_amount as f64 * multiplier;
 
+    Ok(QuotedPrice {
+        price,
+        price_impact_pct: quote.price_impact_pct,
+        route_fee_ui: route_fee_ui(&quote, trading_config, price),
+        quote,
+    })
 }
 
+// buy the base token with USDC
 // e.g. price(USD) for 1 ETH asking for 0.001 ETH
 // e.g. 43.11 USD for 1 SOL
 // min(buy)
-async fn calc_price_exactout<'a>(jupiter: &JupiterV4<'a>) -> f64 {
+async fn calc_buy_price(quoter: &JupiterQuoter, trading_config: &TradingConfig, base_quantity_ui: f64) -> anyhow::Result<QuotedPrice> {
 
     let usd_decimals = 6;
-    let decimals = BASE_DECIMALS - usd_decimals;
+    let decimals = trading_config.base_decimals - usd_decimals;
     let multiplier = 10f64.powf(decimals.into()) as f64;
 
-    const slippage_bps: u64 = 5;
-    const amount: u64 = 100000;
-    let route: QueryRoute = jupiter
-        .quote(
-            Pubkey::from_str(MINT_ADDRESS_INPUT).unwrap(),
-            Pubkey::from_str(MINT_ADDRESS_OUTPUT).unwrap(),
-            amount, slippage_bps, JupiterSwapMode::ExactOut, true)
-        .await.unwrap();
+    let quote = quote_swap_leg(quoter, trading_config, QuoteSide::Buy, base_quantity_ui).await?;
 
-    let price = route.in_amount.parse::<u64>().unwrap() as f64 / route.out_amount.parse::<u64>().unwrap() as f64 * multiplier;
+    // USDC in per token out
+    let price = quote.in_amount as f64 / quote.out_amount as f64 * multiplier;
 
-    // route: QueryRoute { in_amount: "4311", out_amount: "100000",
-    // price_impact_pct: 0.002647819591813372, market_infos:
-    // [QueryMarketInfo { id: "C7AD8EHcbKvFL3zw2z4YKKn1ZMTCYqkThZGtY3hPajsd",
-    // label: "Orca (Whirlpools)", input_mint: "EPjFWdd5AufqSSqeM2qN1xzybapC8G4wEGGkZwyTDt1v"
-    // , output_mint: "So11111111111111111111111111111111111111112", not_enough_liquidity: false,
-    // in_amount: "4311", out_amount: "100000", min_in_amount: None, min_out_amount: None,
-    // price_impact_pct: Some(0.002647819591813326),
-    // lp_fee: QueryFee { amount: "11", mint: "EPjFWdd5AufqSSqeM2qN1xzybapC8G4wEGGkZwyTDt1v", pct: Some(0.0025) }, platform_fee: QueryFee {
+    Ok(QuotedPrice {
+        price,
+        price_impact_pct: quote.price_impact_pct,
+        route_fee_ui: route_fee_ui(&quote, trading_config, price),
+        quote,
+    })
+}
 
-    price
+// quote for the size actually traded
+pub async fn call_buy(quoter: &JupiterQuoter, trading_config: &TradingConfig) -> anyhow::Result<SwapBuyPrice> {
+    call_buy_for_size(quoter, trading_config, trading_config.base_qty_ui).await
+}
 
+pub async fn call_sell(quoter: &JupiterQuoter, trading_config: &TradingConfig) -> anyhow::Result<SwapSellPrice> {
+    call_sell_for_size(quoter, trading_config, trading_config.base_qty_ui).await
 }
 
-// see mango-v4 lib/client/src/jupiter.rs
-#[derive(Deserialize, Serialize, Debug, Clone)]
-#[serde(rename_all = "camelCase")]
-struct SwapQueryResultRaw {
-    in_amount: String,
-    out_amount: String,
+pub async fn call_buy_for_size(quoter: &JupiterQuoter, trading_config: &TradingConfig, base_quantity_ui: f64) -> anyhow::Result<SwapBuyPrice> {
+
+    let quoted = calc_buy_price(quoter, trading_config, base_quantity_ui).await?;
+
+    Ok(SwapBuyPrice {
+        price: quoted.price,
+        base_quantity_ui,
+        in_amount: quoted.quote.in_amount,
+        out_amount: quoted.quote.out_amount,
+        price_impact_pct: quoted.price_impact_pct,
+        route_fee_ui: quoted.route_fee_ui,
+        approx_timestamp: quoted.quote.quoted_at,
+        quote: Arc::new(quoted.quote),
+    })
 }
 
-struct SwapQueryResult {
-    in_amount: f64,
-    out_amount: f64,
+pub async fn call_sell_for_size(quoter: &JupiterQuoter, trading_config: &TradingConfig, base_quantity_ui: f64) -> anyhow::Result<SwapSellPrice> {
+
+    let quoted = calc_sell_price(quoter, trading_config, base_quantity_ui).await?;
+
+    Ok(SwapSellPrice {
+        price: quoted.price,
+        base_quantity_ui,
+        in_amount: quoted.quote.in_amount,
+        out_amount: quoted.quote.out_amount,
+        price_impact_pct: quoted.price_impact_pct,
+        route_fee_ui: quoted.route_fee_ui,
+        approx_timestamp: quoted.quote.quoted_at,
+        quote: Arc::new(quoted.quote),
+    })
 }
 
-impl From<SwapQueryResultRaw> for SwapQueryResult {
-    fn from(value: SwapQueryResultRaw) -> Self {
-        SwapQueryResult {
-            in_amount: value.in_amount.parse::<f64>().unwrap(),
-            out_amount: value.out_amount.parse::<f64>().unwrap(),
-        }
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use crate::services::trading_config::test_trading_config;
+    use super::*;
+
+    const USDC: &str = "EPjFWdd5AufqSSqeM2qN1xzybapC8G4wEGGkZwyTDt1v";
+    const SOL: &str = "So11111111111111111111111111111111111111112";
+
+    // minimal stand-in for GET /quote: buys SOL for 101 USDC, sells SOL for 99 USDC;
+    // returns the base url and the query parameters of all requests
+    async fn start_jupiter_stand_in() -> (String, Arc<Mutex<Vec<HashMap<String, String>>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+
+        let seen = requests.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0u8; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    let n = stream.read(&mut buf).await.unwrap();
+                    if n == 0 {
+                        break;
+                    }
+                    request.extend_from_slice(&buf[..n]);
+                }
+                let request = String::from_utf8(request).unwrap();
+                let target = request.split_whitespace().nth(1).unwrap().to_string();
+                let query: HashMap<String, String> = url::Url::parse(&format!("http://localhost{}", target)).unwrap()
+                    .query_pairs().into_owned().collect();
+                seen.lock().unwrap().push(query.clone());
+
+                let amount: u64 = query["amount"].parse().unwrap();
+                let (status, body) = match (query["inputMint"].as_str(), query["swapMode"].as_str()) {
+                    (USDC, "ExactOut") => ("200 OK", serde_json::json!({
+                        "inAmount": (amount as u128 * 101_000_000 / 1_000_000_000).to_string(),
+                        "outAmount": amount.to_string(),
+                        "priceImpactPct": "0.001",
+                        "routePlan": [{"swapInfo": {"feeAmount": "20000", "feeMint": USDC}}],
+                    })),
+                    (SOL, "ExactIn") => ("200 OK", serde_json::json!({
+                        "inAmount": amount.to_string(),
+                        "outAmount": (amount as u128 * 99_000_000 / 1_000_000_000).to_string(),
+                        "priceImpactPct": "0.002",
+                        "routePlan": [{"swapInfo": {"feeAmount": "1000000", "feeMint": SOL}}],
+                    })),
+                    (USDC, "ExactIn") => ("200 OK", serde_json::json!({
+                        "inAmount": amount.to_string(),
+                        "outAmount": (amount as u128 * 1_000_000_000 / 101_000_000).to_string(),
+                        "priceImpactPct": "0.001",
+                        "routePlan": [],
+                    })),
+                    (SOL, "ExactOut") => ("200 OK", serde_json::json!({
+                        "inAmount": (amount as u128 * 1_000_000_000 / 99_000_000).to_string(),
+                        "outAmount": amount.to_string(),
+                        "priceImpactPct": "0.002",
+                        "routePlan": [],
+                    })),
+                    _ => ("400 Bad Request", serde_json::json!({"error": "unexpected direction"})),
+                };
+                let body = body.to_string();
+                let response = format!("HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status, body.len(), body);
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+
+        (base_url, requests)
     }
-}
 
-pub async fn call_buy<'a>(jupiter: &JupiterV4<'a>) -> SwapBuyPrice {
+    #[tokio::test]
+    async fn buy_quotes_usdc_to_token_exact_out() {
+        let (base_url, requests) = start_jupiter_stand_in().await;
+        let quoter = JupiterQuoter::new(&base_url);
+
+        let buy = call_buy_for_size(&quoter, &test_trading_config(), 0.5).await.unwrap();
 
-    let price = calc_price_exactin(jupiter).await;
+        let requests = requests.lock().unwrap();
+        assert_eq!(1, requests.len());
+        assert_eq!(USDC, requests[0]["inputMint"]);
+        assert_eq!(SOL, requests[0]["outputMint"]);
+        assert_eq!("ExactOut", requests[0]["swapMode"]);
+        assert_eq!("500000000", requests[0]["amount"]);
 
-    SwapBuyPrice {
-        price: price,
-        approx_timestamp: Instant::now(),
+        assert_eq!(50_500_000, buy.in_amount);
+        assert_eq!(500_000_000, buy.out_amount);
+        assert!((buy.price - 101.0).abs() < 1e-9);
+        assert!((buy.route_fee_ui - 0.02).abs() < 1e-9);
+        assert_eq!(SwapMode::ExactOut, buy.quote.swap_mode);
+    }
+
+    #[tokio::test]
+    async fn sell_quotes_token_to_usdc_exact_in() {
+        let (base_url, requests) = start_jupiter_stand_in().await;
+        let quoter = JupiterQuoter::new(&base_url);
+
+        let sell = call_sell_for_size(&quoter, &test_trading_config(), 0.5).await.unwrap();
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(1, requests.len());
+        assert_eq!(SOL, requests[0]["inputMint"]);
+        assert_eq!(USDC, requests[0]["outputMint"]);
+        assert_eq!("ExactIn", requests[0]["swapMode"]);
+        assert_eq!("500000000", requests[0]["amount"]);
+
+        assert_eq!(500_000_000, sell.in_amount);
+        assert_eq!(49_500_000, sell.out_amount);
+        assert!((sell.price - 99.0).abs() < 1e-9);
+        // 0.001 SOL at 99
+        assert!((sell.route_fee_ui - 0.099).abs() < 1e-9);
+        assert_eq!(SwapMode::ExactIn, sell.quote.swap_mode);
+    }
+
+    #[tokio::test]
+    async fn buy_is_priced_above_sell() {
+        let (base_url, _) = start_jupiter_stand_in().await;
+        let quoter = JupiterQuoter::new(&base_url);
+
+        let buy = call_buy(&quoter, &test_trading_config()).await.unwrap();
+        let sell = call_sell(&quoter, &test_trading_config()).await.unwrap();
+        assert!(buy.price > sell.price);
     }
-}
 
-pub async fn call_sell<'a>(jupiter: &JupiterV4<'a>) -> SwapSellPrice {
+    #[tokio::test]
+    async fn sell_exact_out_asks_for_quoted_usdc() {
+        let (base_url, requests) = start_jupiter_stand_in().await;
+        let quoter = JupiterQuoter::new(&base_url);
+        let trading_config = TradingConfig {
+            swap_sell_mode: SwapMode::ExactOut,
+            ..test_trading_config()
+        };
 
-    let res = calc_price_exactout(jupiter).await;
+        let quote = quote_swap_leg(&quoter, &trading_config, QuoteSide::Sell, 0.5).await.unwrap();
 
-    SwapSellPrice {
-        price: res,
-        approx_timestamp: Instant::now(),
+        let requests = requests.lock().unwrap();
+        assert_eq!(2, requests.len());
+        assert_eq!(SOL, requests[1]["inputMint"]);
+        assert_eq!("ExactOut", requests[1]["swapMode"]);
+        assert_eq!("49500000", requests[1]["amount"]);
+        assert_eq!(SwapMode::ExactOut, quote.swap_mode);
+        assert_eq!(49_500_000, quote.out_amount);
+        assert_eq!(500_000_000, quote.in_amount);
     }
 }
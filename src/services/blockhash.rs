@@ -5,22 +5,30 @@ use std::{
     sync::{Arc, RwLock},
     time::Duration,
 };
-use tokio::{
-    spawn,
-    time::{sleep, timeout},
-};
+use tokio::time::{sleep, timeout};
+use crate::supervisor::{RESTART_WITH_BACKOFF, Supervisor};
 
 const RETRY: Duration = Duration::from_millis(5 * DEFAULT_MS_PER_SLOT);
 const TIMEOUT: Duration = Duration::from_secs(10);
 
-async fn poll_loop(client: Arc<RpcClient>, blockhash: Arc<RwLock<Hash>>) {
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct LatestBlockhash {
+    pub blockhash: Hash,
+    // transactions using this blockhash expire once the block height exceeds this value
+    pub last_valid_block_height: u64,
+}
+
+async fn poll_loop(client: Arc<RpcClient>, blockhash: Arc<RwLock<LatestBlockhash>>) {
     loop {
-        match timeout(TIMEOUT, client.get_latest_blockhash()).await {
-            Ok(Ok(new_blockhash)) => {
+        match timeout(TIMEOUT, client.get_latest_blockhash_with_commitment(client.commitment())).await {
+            Ok(Ok((new_blockhash, last_valid_block_height))) => {
                 let mut shared_blockhash = blockhash.write().unwrap();
-                if new_blockhash != *shared_blockhash {
-                    debug!("blockhash update {:?}", new_blockhash);
-                    *shared_blockhash = new_blockhash;
+                if new_blockhash != shared_blockhash.blockhash {
+                    debug!("blockhash update {:?} (last valid block height {})", new_blockhash, last_valid_block_height);
+                    *shared_blockhash = LatestBlockhash {
+                        blockhash: new_blockhash,
+                        last_valid_block_height,
+                    };
                 }
             }
             Ok(Err(e)) => {
@@ -37,26 +45,29 @@ async fn poll_loop(client: Arc<RpcClient>, blockhash: Arc<RwLock<Hash>>) {
     }
 }
 
-pub async fn start_blockhash_service(rpc_url: String) -> Arc<RwLock<Hash>> {
+pub async fn start_blockhash_service(rpc_url: String, supervisor: &Supervisor) -> Arc<RwLock<LatestBlockhash>> {
     let rpc_client = Arc::new(RpcClient::new_with_commitment(
         rpc_url,
         CommitmentConfig::confirmed(),
     ));
 
     // get the first blockhash
-    let blockhash = Arc::new(RwLock::new(
-        rpc_client
-            .get_latest_blockhash()
-            .await
-            .expect("fetch initial blockhash"),
-    ));
+    let (initial_blockhash, last_valid_block_height) = rpc_client
+        .get_latest_blockhash_with_commitment(rpc_client.commitment())
+        .await
+        .expect("fetch initial blockhash");
+    let blockhash = Arc::new(RwLock::new(LatestBlockhash {
+        blockhash: initial_blockhash,
+        last_valid_block_height,
+    }));
 
     // launch task
-    let _join_hdl = {
+    {
         // create a thread-local reference to blockhash
         let blockhash_c = blockhash.clone();
-        spawn(async move { poll_loop(rpc_client, blockhash_c).await })
-    };
+        supervisor.supervise("blockhash poller", RESTART_WITH_BACKOFF,
+            move || poll_loop(rpc_client.clone(), blockhash_c.clone()));
+    }
 
     blockhash
 }
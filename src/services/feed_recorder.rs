@@ -0,0 +1,296 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use anyhow::Context;
+use chrono::Utc;
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::sync::oneshot;
+use crate::services::price_source::{PriceQuote, QuoteSide};
+
+// start a new file when one of the limits is reached
+const MAX_FILE_BYTES: u64 = 256 * 1024 * 1024;
+const MAX_FILE_AGE: Duration = Duration::from_secs(60 * 60);
+
+// one line of a recorded feed file (json lines)
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum RecordedEvent {
+    // raw text message as received from the orderbook websocket
+    Orderbook {
+        timestamp_ms: i64,
+        market: String,
+        raw: String,
+    },
+    SwapBuy {
+        timestamp_ms: i64,
+        pair: String,
+        price: f64,
+        base_quantity_ui: f64,
+        price_impact_pct: f64,
+        route_fee_ui: f64,
+    },
+    SwapSell {
+        timestamp_ms: i64,
+        pair: String,
+        price: f64,
+        base_quantity_ui: f64,
+        price_impact_pct: f64,
+        route_fee_ui: f64,
+    },
+}
+
+impl RecordedEvent {
+    pub fn timestamp_ms(&self) -> i64 {
+        match self {
+            RecordedEvent::Orderbook { timestamp_ms, .. } => *timestamp_ms,
+            RecordedEvent::SwapBuy { timestamp_ms, .. } => *timestamp_ms,
+            RecordedEvent::SwapSell { timestamp_ms, .. } => *timestamp_ms,
+        }
+    }
+}
+
+enum RecorderMessage {
+    Event(RecordedEvent),
+    // answered once everything sent before is written to disk
+    Flush(oneshot::Sender<()>),
+}
+
+// cheap to clone; all clones feed the same writer task
+#[derive(Clone)]
+pub struct FeedRecorder {
+    sender: UnboundedSender<RecorderMessage>,
+}
+
+impl FeedRecorder {
+
+    // spawns the writer task; files are named feed-<utc start time>.jsonl
+    pub fn start(record_dir: &str) -> anyhow::Result<Self> {
+        fs::create_dir_all(record_dir)
+            .with_context(|| format!("Can't create record directory <{}>", record_dir))?;
+        let (sender, receiver) = unbounded_channel();
+        tokio::spawn(write_recorded_events(PathBuf::from(record_dir), receiver));
+        info!("Recording feeds to {}", record_dir);
+        Ok(FeedRecorder { sender })
+    }
+
+    pub fn record_orderbook_message(&self, market: &str, raw: &str) {
+        self.record(RecordedEvent::Orderbook {
+            timestamp_ms: Utc::now().timestamp_millis(),
+            market: market.to_string(),
+            raw: raw.to_string(),
+        });
+    }
+
+    pub fn record_swap_quote(&self, pair: &str, quote: &PriceQuote) {
+        let timestamp_ms = Utc::now().timestamp_millis();
+        let pair = pair.to_string();
+        self.record(match quote.side {
+            QuoteSide::Buy => RecordedEvent::SwapBuy {
+                timestamp_ms,
+                pair,
+                price: quote.price,
+                base_quantity_ui: quote.base_quantity_ui,
+                price_impact_pct: quote.price_impact_pct,
+                route_fee_ui: quote.fee_ui,
+            },
+            QuoteSide::Sell => RecordedEvent::SwapSell {
+                timestamp_ms,
+                pair,
+                price: quote.price,
+                base_quantity_ui: quote.base_quantity_ui,
+                price_impact_pct: quote.price_impact_pct,
+                route_fee_ui: quote.fee_ui,
+            },
+        });
+    }
+
+    // waits until all events recorded so far are written
+    pub async fn flush(&self) {
+        let (done, flushed) = oneshot::channel();
+        if self.sender.send(RecorderMessage::Flush(done)).is_err() || flushed.await.is_err() {
+            warn!("feed recorder stopped, can't flush");
+        }
+    }
+
+    fn record(&self, event: RecordedEvent) {
+        if self.sender.send(RecorderMessage::Event(event)).is_err() {
+            warn!("feed recorder stopped, dropping recorded event");
+        }
+    }
+}
+
+struct RecordFile {
+    writer: BufWriter<File>,
+    bytes_written: u64,
+    opened_at: Instant,
+}
+
+impl RecordFile {
+    fn create(record_dir: &Path) -> anyhow::Result<Self> {
+        let path = record_dir.join(format!("feed-{}.jsonl", Utc::now().format("%Y%m%d-%H%M%S%.3f")));
+        // append-only; never truncate an existing recording
+        let file = OpenOptions::new().create(true).append(true).open(&path)
+            .with_context(|| format!("Can't open record file <{}>", path.display()))?;
+        info!("Recording feeds to new file {}", path.display());
+        Ok(RecordFile {
+            writer: BufWriter::new(file),
+            bytes_written: 0,
+            opened_at: Instant::now(),
+        })
+    }
+
+    fn needs_rotation(&self, now: Instant) -> bool {
+        self.bytes_written >= MAX_FILE_BYTES || now.saturating_duration_since(self.opened_at) >= MAX_FILE_AGE
+    }
+}
+
+async fn write_recorded_events(record_dir: PathBuf, mut receiver: UnboundedReceiver<RecorderMessage>) {
+    let mut record_file: Option<RecordFile> = None;
+
+    while let Some(message) = receiver.recv().await {
+        // flush once the burst of queued events is written
+        let mut flush_requests = vec![];
+        let mut next = Some(message);
+        while let Some(message) = next {
+            match message {
+                RecorderMessage::Event(event) => {
+                    if let Err(err) = write_event(&record_dir, &mut record_file, &event, Instant::now()) {
+                        error!("failed to record feed event: {:#}", err);
+                        // try with a fresh file next time
+                        record_file = None;
+                    }
+                }
+                RecorderMessage::Flush(done) => flush_requests.push(done),
+            }
+            next = receiver.try_recv().ok();
+        }
+        if let Some(file) = record_file.as_mut() {
+            if let Err(err) = file.writer.flush() {
+                error!("failed to flush record file: {}", err);
+            }
+        }
+        for done in flush_requests {
+            let _ = done.send(());
+        }
+    }
+
+    info!("Feed recorder exited");
+}
+
+fn write_event(record_dir: &Path, record_file: &mut Option<RecordFile>, event: &RecordedEvent, now: Instant) -> anyhow::Result<()> {
+    if record_file.as_ref().map(|file| file.needs_rotation(now)).unwrap_or(false) {
+        if let Some(mut file) = record_file.take() {
+            file.writer.flush()?;
+        }
+    }
+    if record_file.is_none() {
+        *record_file = Some(RecordFile::create(record_dir)?);
+    }
+    let file = record_file.as_mut().unwrap();
+
+    let line = serde_json::to_string(event)?;
+    file.writer.write_all(line.as_bytes())?;
+    file.writer.write_all(b"\n")?;
+    file.bytes_written += line.len() as u64 + 1;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn record_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("feed-recorder-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn recorded_files(dir: &Path) -> Vec<Vec<RecordedEvent>> {
+        let mut paths = fs::read_dir(dir).unwrap().map(|entry| entry.unwrap().path()).collect::<Vec<_>>();
+        paths.sort();
+        paths.iter()
+            .map(|path| fs::read_to_string(path).unwrap().lines()
+                .map(|line| serde_json::from_str(line).unwrap())
+                .collect())
+            .collect()
+    }
+
+    fn orderbook(timestamp_ms: i64) -> RecordedEvent {
+        RecordedEvent::Orderbook { timestamp_ms, market: "market".to_string(), raw: "{}".to_string() }
+    }
+
+    // file names have millisecond resolution
+    fn next_file_name() {
+        std::thread::sleep(Duration::from_millis(2));
+    }
+
+    #[test]
+    fn rotates_when_the_file_is_full() {
+        let dir = record_dir("size");
+        let mut record_file = None;
+        let now = Instant::now();
+        write_event(&dir, &mut record_file, &orderbook(1), now).unwrap();
+        write_event(&dir, &mut record_file, &orderbook(2), now).unwrap();
+
+        record_file.as_mut().unwrap().bytes_written = MAX_FILE_BYTES;
+        next_file_name();
+        write_event(&dir, &mut record_file, &orderbook(3), now).unwrap();
+        record_file.as_mut().unwrap().writer.flush().unwrap();
+
+        let timestamps = recorded_files(&dir).iter()
+            .map(|events| events.iter().map(RecordedEvent::timestamp_ms).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        assert_eq!(vec![vec![1, 2], vec![3]], timestamps);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rotates_when_the_file_is_old() {
+        let dir = record_dir("age");
+        let mut record_file = None;
+        let opened_at = Instant::now();
+        write_event(&dir, &mut record_file, &orderbook(1), opened_at).unwrap();
+        write_event(&dir, &mut record_file, &orderbook(2), opened_at + MAX_FILE_AGE - Duration::from_secs(1)).unwrap();
+
+        next_file_name();
+        write_event(&dir, &mut record_file, &orderbook(3), opened_at + MAX_FILE_AGE).unwrap();
+        record_file.as_mut().unwrap().writer.flush().unwrap();
+
+        let timestamps = recorded_files(&dir).iter()
+            .map(|events| events.iter().map(RecordedEvent::timestamp_ms).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        assert_eq!(vec![vec![1, 2], vec![3]], timestamps);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn flushed_events_can_be_read_back() {
+        let dir = record_dir("flush");
+        let recorder = FeedRecorder::start(dir.to_str().unwrap()).unwrap();
+        recorder.record_orderbook_message("market", r#"{"slot": 1}"#);
+        recorder.record_swap_quote("SOL-PERP/SOL", &PriceQuote {
+            side: QuoteSide::Sell,
+            price: 104.0,
+            base_quantity_ui: 1.0,
+            price_impact_pct: 0.001,
+            fee_ui: 0.02,
+            observed_at: Instant::now(),
+            slot: None,
+            swap_quote: None,
+        });
+        recorder.flush().await;
+
+        let files = recorded_files(&dir);
+        assert_eq!(1, files.len());
+        let [RecordedEvent::Orderbook { market, raw, .. }, RecordedEvent::SwapSell { pair, price, route_fee_ui, .. }] = &files[0][..] else {
+            panic!("unexpected events {:?}", files[0]);
+        };
+        assert_eq!(("market", r#"{"slot": 1}"#), (market.as_str(), raw.as_str()));
+        assert_eq!(("SOL-PERP/SOL", 104.0, 0.02), (pair.as_str(), *price, *route_fee_ui));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
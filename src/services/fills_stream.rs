@@ -0,0 +1,187 @@
+use std::time::Duration;
+use anyhow::anyhow;
+use log::{debug, info, trace, warn};
+use serde_json::{from_str, json, Value};
+use solana_sdk::pubkey::Pubkey;
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::time::{Instant, timeout_at};
+use url::Url;
+use websocket_tungstenite_retry::websocket_stable::{StableWebSocket, WsMessage};
+use crate::services::fill_update_event::FillUpdateEvent;
+use crate::supervisor::{RESTART_WITH_BACKOFF, Supervisor};
+use crate::trade_sequence::COMPLETE_FILL_RATIO;
+
+// buffer for fill events not yet consumed by the waiters
+const FILLS_CHANNEL_CAPACITY: usize = 1024;
+
+// all fills of one taker order (a market order might match several makers)
+#[derive(Debug, Clone)]
+pub struct PerpFill {
+    pub client_order_id: u64,
+    pub events: Vec<FillUpdateEvent>,
+}
+
+impl PerpFill {
+
+    // base quantity ui
+    pub fn quantity(&self) -> f64 {
+        self.events.iter().map(|fill| fill.event.quantity).sum()
+    }
+
+    // volume-weighted price
+    pub fn avg_price(&self) -> f64 {
+        let notional: f64 = self.events.iter().map(|fill| fill.event.price * fill.event.quantity).sum();
+        notional / self.quantity()
+    }
+
+    // taker fees in quote ui (taker_fee is a rate)
+    pub fn fees(&self) -> f64 {
+        self.events.iter().map(|fill| fill.event.price * fill.event.quantity * fill.event.taker_fee).sum()
+    }
+
+    // quote ui received (positive) or paid (negative) including fees
+    pub fn quote_cash_flow(&self) -> f64 {
+        self.events.iter().map(|fill| {
+            let notional = fill.event.price * fill.event.quantity;
+            let fee = notional * fill.event.taker_fee;
+            if fill.event.taker_side == "ask" { notional - fee } else { -notional - fee }
+        }).sum()
+    }
+}
+
+#[derive(Clone)]
+pub struct FillsFeed {
+    sender: broadcast::Sender<FillUpdateEvent>,
+}
+
+impl FillsFeed {
+
+    // spawns the listener, restarted by the supervisor whenever it ends
+    pub fn start(market_id: String, supervisor: &Supervisor) -> Self {
+        let (sender, _) = broadcast::channel(FILLS_CHANNEL_CAPACITY);
+        let fills = sender.clone();
+        supervisor.supervise(&format!("fills feed {}", market_id), RESTART_WITH_BACKOFF,
+            move || listen_fills_feed(market_id.clone(), fills.clone()));
+        FillsFeed { sender }
+    }
+
+    // subscribe before placing the order to not miss the fill
+    pub fn subscribe(&self) -> FillsSubscription {
+        FillsSubscription {
+            receiver: self.sender.subscribe(),
+        }
+    }
+}
+
+pub struct FillsSubscription {
+    receiver: broadcast::Receiver<FillUpdateEvent>,
+}
+
+impl FillsSubscription {
+
+    // collects fills of our taker order until the expected quantity is reached or the time is up;
+    // a partial fill is returned if the order was not fully filled
+    pub async fn await_fill(mut self, mango_account: &Pubkey, client_order_id: u64,
+                            expected_quantity: f64, max_wait: Duration) -> anyhow::Result<PerpFill> {
+        let mango_account = mango_account.to_string();
+        let deadline = Instant::now() + max_wait;
+        let mut perp_fill = PerpFill {
+            client_order_id,
+            events: vec![],
+        };
+
+        loop {
+            let fill = match timeout_at(deadline, self.receiver.recv()).await {
+                Ok(Ok(fill)) => fill,
+                Ok(Err(RecvError::Lagged(skipped))) => {
+                    warn!("fills subscription lagged, {} fill events skipped", skipped);
+                    continue;
+                }
+                Ok(Err(RecvError::Closed)) => return Err(anyhow!("fills feed closed")),
+                Err(_) => break,
+            };
+
+            if fill.event.taker != mango_account || fill.event.taker_client_order_id as u64 != client_order_id {
+                continue;
+            }
+
+            // TODO add assertions from https://github.com/blockworks-foundation/mango-v4/blob/max/mm/ts/client/scripts/mm/market-maker.ts#L185
+            match fill.status.as_str() {
+                "new" => {
+                    debug!("Recorded fill event for client order id: {}", client_order_id);
+                    trace!("Fill Event: {:?}", fill);
+                    perp_fill.events.push(fill);
+                }
+                "revoke" => {
+                    warn!("Fill event for client order id {} was revoked", client_order_id);
+                    perp_fill.events.retain(|existing| existing.event.seq_num != fill.event.seq_num);
+                }
+                status => warn!("unexpected fill status <{}>", status),
+            }
+
+            if perp_fill.quantity() >= expected_quantity * COMPLETE_FILL_RATIO {
+                return Ok(perp_fill);
+            }
+        }
+
+        if perp_fill.events.is_empty() {
+            return Err(anyhow!("Can't find fill event for client order id {} within {:?}", client_order_id, max_wait));
+        }
+        warn!("order with client order id {} only partially filled: {} of {}",
+            client_order_id, perp_fill.quantity(), expected_quantity);
+        Ok(perp_fill)
+    }
+}
+
+// requires running "service-mango-fills"
+async fn listen_fills_feed(market_id: String, fills: broadcast::Sender<FillUpdateEvent>) {
+    let subscription_request = json!({
+            "command": "subscribe",
+            "marketId": market_id,
+            "headUpdates": true,
+        });
+
+    // the supervisor retries with backoff
+    let mut socket = match StableWebSocket::new_with_timeout(
+        Url::parse("wss://api.mngo.cloud/fills/v1/").unwrap(),
+        subscription_request, Duration::from_secs(5)).await {
+        Ok(socket) => socket,
+        Err(err) => {
+            warn!("Can't connect to fills feed for market {}: {:?}", market_id, err);
+            return;
+        }
+    };
+    info!("Subscribed to fills feed for market {}", market_id);
+
+    let mut messages = socket.subscribe_message_channel();
+    loop {
+        let ws_message = match messages.recv().await {
+            Ok(ws_message) => ws_message,
+            Err(RecvError::Lagged(skipped)) => {
+                warn!("fills websocket lagged, {} messages skipped", skipped);
+                continue;
+            }
+            Err(RecvError::Closed) => break,
+        };
+        let WsMessage::Text(plain) = ws_message else { continue; };
+
+        let Ok(json) = from_str::<Value>(&plain) else {
+            warn!("Can't parse fills message to JSON <{}>", plain);
+            continue;
+        };
+        // skip subscription responses and checkpoints
+        if json.get("event").is_none() {
+            continue;
+        }
+
+        match serde_json::from_value::<FillUpdateEvent>(json) {
+            // no receivers is fine
+            Ok(fill) => { let _ = fills.send(fill); }
+            Err(err) => warn!("Can't convert fill event <{}>: {}", plain, err),
+        }
+    }
+
+    warn!("Fills WebSocket stream for market {} exited!", market_id);
+    socket.join().await;
+}
@@ -0,0 +1,148 @@
+use std::fmt;
+use std::time::{Duration, Instant};
+use anyhow::Context;
+use serde::Deserialize;
+use serde_json::Value;
+use solana_sdk::pubkey::Pubkey;
+
+pub const DEFAULT_JUPITER_V6_URL: &str = "https://quote-api.jup.ag/v6";
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SwapMode {
+    // amount is the input amount, output is quoted
+    ExactIn,
+    // amount is the output amount, input is quoted
+    ExactOut,
+}
+
+impl fmt::Display for SwapMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SwapMode::ExactIn => write!(f, "ExactIn"),
+            SwapMode::ExactOut => write!(f, "ExactOut"),
+        }
+    }
+}
+
+// quote from the same v6 endpoint the swap is executed with; the raw response is kept to execute exactly this route
+#[derive(Debug, Clone)]
+pub struct SwapQuote {
+    pub input_mint: Pubkey,
+    pub output_mint: Pubkey,
+    pub swap_mode: SwapMode,
+    // native amounts
+    pub in_amount: u64,
+    pub out_amount: u64,
+    // e.g. 0.0026 = 0.26%
+    pub price_impact_pct: f64,
+    // slot the router priced the route at
+    pub context_slot: Option<u64>,
+    // native fee amounts of all route steps by mint
+    pub fees: Vec<(String, u64)>,
+    pub response: Value,
+    pub quoted_at: Instant,
+}
+
+impl SwapQuote {
+    pub fn age(&self) -> Duration {
+        self.quoted_at.elapsed()
+    }
+
+    // the amount that was fixed by the request
+    pub fn requested_amount(&self) -> u64 {
+        match self.swap_mode {
+            SwapMode::ExactIn => self.in_amount,
+            SwapMode::ExactOut => self.out_amount,
+        }
+    }
+
+    // how much worse the other quote is in bps, negative if it is better
+    pub fn deviation_bps(&self, other: &SwapQuote) -> f64 {
+        match self.swap_mode {
+            // less output is worse
+            SwapMode::ExactIn => (self.out_amount as f64 - other.out_amount as f64) / self.out_amount as f64 * 10_000.0,
+            // more input is worse
+            SwapMode::ExactOut => (other.in_amount as f64 - self.in_amount as f64) / self.in_amount as f64 * 10_000.0,
+        }
+    }
+}
+
+// see https://station.jup.ag/api-v6/get-quote
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct QuoteResponseRaw {
+    in_amount: String,
+    out_amount: String,
+    price_impact_pct: String,
+    route_plan: Vec<RoutePlanRaw>,
+    #[serde(default)]
+    context_slot: Option<u64>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct RoutePlanRaw {
+    swap_info: SwapInfoRaw,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct SwapInfoRaw {
+    fee_amount: String,
+    fee_mint: String,
+}
+
+pub struct JupiterQuoter {
+    http_client: reqwest::Client,
+    base_url: String,
+}
+
+impl JupiterQuoter {
+
+    pub fn new(base_url: &str) -> Self {
+        JupiterQuoter {
+            http_client: reqwest::Client::builder().timeout(REQUEST_TIMEOUT).build().unwrap(),
+            base_url: base_url.trim_end_matches('/').to_string(),
+        }
+    }
+
+    pub async fn quote(&self, input_mint: Pubkey, output_mint: Pubkey, amount: u64, swap_mode: SwapMode,
+                       slippage_bps: u64, only_direct_routes: bool) -> anyhow::Result<SwapQuote> {
+        let response = self.http_client
+            .get(format!("{}/quote", self.base_url))
+            .query(&[
+                ("inputMint", input_mint.to_string()),
+                ("outputMint", output_mint.to_string()),
+                ("amount", amount.to_string()),
+                ("swapMode", swap_mode.to_string()),
+                ("slippageBps", slippage_bps.to_string()),
+                ("onlyDirectRoutes", only_direct_routes.to_string()),
+            ])
+            .send().await
+            .context("jupiter quote request")?
+            .error_for_status()
+            .context("jupiter quote response")?
+            .json::<Value>().await
+            .context("jupiter quote response body")?;
+
+        let raw: QuoteResponseRaw = serde_json::from_value(response.clone())
+            .with_context(|| format!("Can't parse jupiter quote <{}>", response))?;
+
+        Ok(SwapQuote {
+            input_mint,
+            output_mint,
+            swap_mode,
+            in_amount: raw.in_amount.parse().context("inAmount")?,
+            out_amount: raw.out_amount.parse().context("outAmount")?,
+            price_impact_pct: raw.price_impact_pct.parse().context("priceImpactPct")?,
+            context_slot: raw.context_slot,
+            fees: raw.route_plan.iter()
+                .map(|step| (step.swap_info.fee_mint.clone(), step.swap_info.fee_amount.parse().unwrap_or(0)))
+                .collect(),
+            response,
+            quoted_at: Instant::now(),
+        })
+    }
+}
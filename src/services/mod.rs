@@ -2,9 +2,15 @@
 
 pub mod asset_price_swap;
 pub mod orderbook_stream;
+pub mod onchain_orderbook;
 pub mod perp_orders;
+pub mod price_source;
 pub mod fill_update_event;
+pub mod fills_stream;
+pub mod jupiter_quote;
+pub mod feed_recorder;
 pub mod blockhash;
+pub mod slot_tracker;
 pub mod swap_orders;
 pub mod transactions;
 pub mod trading_config;
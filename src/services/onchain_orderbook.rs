@@ -0,0 +1,202 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use anyhow::{anyhow, Context};
+use chrono::Utc;
+use futures::StreamExt;
+use log::{debug, info, warn};
+use mango_v4::accounts_zerocopy::KeyedAccountSharedData;
+use mango_v4::state::{BookSide, PerpMarket};
+use solana_account_decoder::UiAccountEncoding;
+use solana_client::nonblocking::pubsub_client::PubsubClient;
+use solana_client::rpc_config::RpcAccountInfoConfig;
+use solana_sdk::account::{Account, AccountSharedData};
+use solana_sdk::commitment_config::CommitmentConfig;
+use tokio::sync::RwLock;
+use tokio::time::sleep;
+use crate::numerics::{ConversionConf, lot_price_to_ui, lot_to_quantity_ui};
+use crate::services::orderbook_stream::{PerpOrderbook, PriceInfo, publish_top_of_book, TopOfBookEvents};
+
+const RECONNECT_DELAY: Duration = Duration::from_secs(2);
+// anchor account discriminator
+const DISCRIMINATOR_LEN: usize = 8;
+
+// alternative to listen_perp_market_feed which needs only a plain RPC node:
+// subscribes to the bids/asks BookSide accounts of the perp market and decodes them
+pub async fn listen_onchain_perp_market(ws_url: String, perp_market: PerpMarket,
+                                        orderbook: Arc<RwLock<PerpOrderbook>>,
+                                        highest_bid_price: Arc<RwLock<Option<PriceInfo>>>,
+                                        lowest_ask_price: Arc<RwLock<Option<PriceInfo>>>,
+                                        top_of_book_events: TopOfBookEvents) {
+    loop {
+        if let Err(err) = subscribe_book_sides(&ws_url, &perp_market, &orderbook, &highest_bid_price, &lowest_ask_price,
+                                               &top_of_book_events).await {
+            warn!("on-chain orderbook subscription for {} failed: {:#}", perp_market.name(), err);
+        }
+
+        // stop publishing prices until both sides were received again
+        *highest_bid_price.write().await = None;
+        *lowest_ask_price.write().await = None;
+        info!("Resubscribing to on-chain orderbook of {} in {:?} ...", perp_market.name(), RECONNECT_DELAY);
+        sleep(RECONNECT_DELAY).await;
+    }
+}
+
+async fn subscribe_book_sides(ws_url: &str, perp_market: &PerpMarket,
+                              orderbook: &RwLock<PerpOrderbook>,
+                              highest_bid_price: &RwLock<Option<PriceInfo>>,
+                              lowest_ask_price: &RwLock<Option<PriceInfo>>,
+                              top_of_book_events: &TopOfBookEvents) -> anyhow::Result<()> {
+    let pubsub_client = PubsubClient::new(ws_url).await
+        .with_context(|| format!("Can't connect to {}", ws_url))?;
+    let config = RpcAccountInfoConfig {
+        encoding: Some(UiAccountEncoding::Base64),
+        commitment: Some(CommitmentConfig::processed()),
+        ..RpcAccountInfoConfig::default()
+    };
+    let (mut bids_stream, bids_unsubscribe) = pubsub_client.account_subscribe(&perp_market.bids, Some(config.clone())).await?;
+    let (mut asks_stream, asks_unsubscribe) = pubsub_client.account_subscribe(&perp_market.asks, Some(config.clone())).await?;
+    let (mut oracle_stream, oracle_unsubscribe) = pubsub_client.account_subscribe(&perp_market.oracle, Some(config)).await?;
+    info!("Subscribed to on-chain orderbook of {} (bids {}, asks {}, oracle {})",
+        perp_market.name(), perp_market.bids, perp_market.asks, perp_market.oracle);
+
+    let lot_conf = ConversionConf::from(*perp_market);
+    // oracle pegged orders are priced off the oracle - the book is only complete once both sides and the oracle were received
+    let mut bids_data: Option<Vec<u8>> = None;
+    let mut asks_data: Option<Vec<u8>> = None;
+    let mut oracle_price_lots: Option<i64> = None;
+
+    let result = loop {
+        let (update, account_name) = tokio::select! {
+            update = bids_stream.next() => (update, "bids"),
+            update = asks_stream.next() => (update, "asks"),
+            update = oracle_stream.next() => (update, "oracle"),
+        };
+        let Some(response) = update else {
+            break Err(anyhow!("account subscription closed"));
+        };
+        let received_at = Instant::now();
+        let Some(account) = response.value.decode::<Account>() else {
+            break Err(anyhow!("Can't decode {} account data", account_name));
+        };
+        // the slot serves as write version - one account update per slot
+        let slot = response.context.slot;
+        debug!("{} update for {} at slot {}", account_name, perp_market.name(), slot);
+        match account_name {
+            "bids" => bids_data = Some(account.data),
+            "asks" => asks_data = Some(account.data),
+            _ => match decode_oracle_price_lots(perp_market, account) {
+                Ok(price_lots) => oracle_price_lots = Some(price_lots),
+                Err(err) => break Err(err),
+            },
+        }
+
+        let (Some(bids_data), Some(asks_data), Some(oracle_price_lots)) = (&bids_data, &asks_data, oracle_price_lots) else {
+            continue;
+        };
+        let (bids, asks) = match decode_book(bids_data, asks_data, lot_conf, oracle_price_lots) {
+            Ok(levels) => levels,
+            Err(err) => break Err(err),
+        };
+
+        let mut book = orderbook.write().await;
+        book.slot = Some(slot);
+        book.replace_bids(&bids);
+        book.replace_asks(&asks);
+        // the oracle updates about every slot, also while the book doesn't change
+        book.mark_alive(received_at, slot);
+        if publish_top_of_book(&book, slot, highest_bid_price, lowest_ask_price).await {
+            // fails only without subscribers
+            let _ = top_of_book_events.send(received_at);
+        }
+    };
+
+    drop(bids_stream);
+    drop(asks_stream);
+    drop(oracle_stream);
+    bids_unsubscribe().await;
+    asks_unsubscribe().await;
+    oracle_unsubscribe().await;
+    result
+}
+
+fn decode_oracle_price_lots(perp_market: &PerpMarket, account: Account) -> anyhow::Result<i64> {
+    let oracle = KeyedAccountSharedData::new(perp_market.oracle, AccountSharedData::from(account));
+    let price = perp_market.oracle_price(&oracle, None)
+        .map_err(|err| anyhow!("Can't read oracle price of {}: {:?}", perp_market.name(), err))?;
+    Ok(perp_market.native_price_to_lot(price))
+}
+
+// pegged prices move with the oracle, so both sides are decoded again on every update
+fn decode_book(bids_data: &[u8], asks_data: &[u8], lot_conf: ConversionConf,
+               oracle_price_lots: i64) -> anyhow::Result<(Vec<(f64, f64)>, Vec<(f64, f64)>)> {
+    Ok((decode_book_side(bids_data, lot_conf, oracle_price_lots)?, decode_book_side(asks_data, lot_conf, oracle_price_lots)?))
+}
+
+// aggregated price levels (ui price, ui quantity) of a BookSide account incl. oracle pegged orders
+fn decode_book_side(data: &[u8], lot_conf: ConversionConf, oracle_price_lots: i64) -> anyhow::Result<Vec<(f64, f64)>> {
+    let size = std::mem::size_of::<BookSide>();
+    if data.len() < DISCRIMINATOR_LEN + size {
+        return Err(anyhow!("BookSide account data too short: {} bytes", data.len()));
+    }
+    let book_side: BookSide = bytemuck::pod_read_unaligned(&data[DISCRIMINATOR_LEN..DISCRIMINATOR_LEN + size]);
+
+    let now_ts = Utc::now().timestamp() as u64;
+    let mut levels: BTreeMap<i64, i64> = BTreeMap::new();
+    for item in book_side.iter_valid(now_ts, Some(oracle_price_lots)) {
+        *levels.entry(item.price_lots).or_default() += item.node.quantity;
+    }
+
+    Ok(levels.into_iter()
+        .map(|(price_lots, base_lots)| (lot_price_to_ui(lot_conf, price_lots), lot_to_quantity_ui(lot_conf, base_lots)))
+        .collect())
+}
+
+#[cfg(test)]
+mod test {
+    use bytemuck::Zeroable;
+    use mango_v4::state::{fixed_price_data, new_node_key, oracle_pegged_price_data, LeafNode, OrderTreeType, PostOrderType, Side};
+    use solana_sdk::pubkey::Pubkey;
+    use super::*;
+
+    // SOL-PERP: 0.01 SOL base lots, 100 lots = 1 USDC
+    fn lot_conf() -> ConversionConf {
+        let mut perp_market = PerpMarket::zeroed();
+        perp_market.base_decimals = 9;
+        perp_market.base_lot_size = 10_000_000;
+        perp_market.quote_lot_size = 100;
+        ConversionConf::from(perp_market)
+    }
+
+    fn leaf(key: u128, quantity: i64) -> LeafNode {
+        // no peg limit, never expires
+        LeafNode::new(0, key, Pubkey::default(), quantity, 1, PostOrderType::Limit, 0, -1, 0)
+    }
+
+    #[test]
+    fn book_side_includes_oracle_pegged_orders() {
+        let mut bids = BookSide::zeroed();
+        bids.nodes.order_tree_type = OrderTreeType::Bids.into();
+        // roots[0] holds fixed price orders, roots[1] oracle pegged ones
+        let [fixed, pegged] = &mut bids.roots;
+        bids.nodes.insert_leaf(fixed, &leaf(new_node_key(Side::Bid, fixed_price_data(10_000).unwrap(), 1), 100)).unwrap();
+        bids.nodes.insert_leaf(fixed, &leaf(new_node_key(Side::Bid, fixed_price_data(9_990).unwrap(), 2), 50)).unwrap();
+        bids.nodes.insert_leaf(pegged, &leaf(new_node_key(Side::Bid, oracle_pegged_price_data(-10), 3), 30)).unwrap();
+        bids.nodes.insert_leaf(pegged, &leaf(new_node_key(Side::Bid, oracle_pegged_price_data(-20), 4), 20)).unwrap();
+        let mut data = vec![0u8; DISCRIMINATOR_LEN];
+        data.extend_from_slice(bytemuck::bytes_of(&bids));
+
+        let lot_conf = lot_conf();
+        let levels = decode_book_side(&data, lot_conf, 10_000).unwrap();
+        let expected = [(9_980, 20), (9_990, 80), (10_000, 100)].iter()
+            .map(|(price_lots, base_lots)| (lot_price_to_ui(lot_conf, *price_lots), lot_to_quantity_ui(lot_conf, *base_lots)))
+            .collect::<Vec<_>>();
+        assert_eq!(expected, levels);
+        assert!((levels[1].0 - 99.9).abs() < 1e-9 && (levels[1].1 - 0.8).abs() < 1e-9);
+
+        // pegged orders follow the oracle
+        let levels = decode_book_side(&data, lot_conf, 10_100).unwrap();
+        let prices = levels.iter().map(|(price, _)| *price).collect::<Vec<_>>();
+        assert_eq!(vec![lot_price_to_ui(lot_conf, 9_990), lot_price_to_ui(lot_conf, 10_000), lot_price_to_ui(lot_conf, 10_080), lot_price_to_ui(lot_conf, 10_090)], prices);
+    }
+}
@@ -11,7 +11,9 @@ use ordered_float::OrderedFloat;
 use serde::{Deserialize, Serialize};
 use serde_json::{from_str, json, Value};
 use tokio::io;
-use tokio::sync::{Mutex, RwLock};
+use tokio::sync::{watch, Mutex, RwLock};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::time::timeout;
 use tokio_tungstenite::{connect_async, tungstenite, WebSocketStream};
 use tokio_tungstenite::tungstenite::{connect, Message, WebSocket, error::Error as WsError};
 use tokio_tungstenite::tungstenite::client::connect_with_config;
@@ -20,16 +22,9 @@ use tokio_tungstenite::tungstenite::Message::Text;
 use tokio_tungstenite::tungstenite::stream::MaybeTlsStream;
 use url::Url;
 use websocket_tungstenite_retry::websocket_stable::{StableWebSocket, WsMessage};
+use crate::services::feed_recorder::FeedRecorder;
 use crate::services::fill_update_event::FillUpdateEvent;
 
-#[derive(Debug, Copy, Clone)]
-pub struct OrderstreamPrice {
-    // ETH in USDC - 1901,59495311
-    pub price: f64,
-    pub quantity: f64,
-    pub approx_timestamp: Instant,
-}
-
 // mango-feeds
 type OrderbookLevel = [f64; 2];
 
@@ -71,14 +66,33 @@ struct Subscriptions {
     pub market_id: String,
 }
 
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct InsufficientDepth {
+    pub requested: f64,
+    pub available: f64,
+}
+
 #[derive(Default)]
-struct PerpOrderbook {
+pub struct PerpOrderbook {
     pub bids: BTreeMap<OrderedFloat<f64>, f64>,
     pub asks: BTreeMap<OrderedFloat<f64>, f64>,
+    // when the last message was applied
+    pub updated_at: Option<Instant>,
+    // slot of the last applied message
+    pub slot: Option<u64>,
+    // when the feed last received a message or heartbeat - advances while the book doesn't change
+    pub alive_at: Option<Instant>,
+    // latest slot the feed was known to be current at
+    pub alive_slot: Option<u64>,
 }
 
 impl PerpOrderbook {
 
+    pub fn mark_alive(&mut self, received_at: Instant, slot: u64) {
+        self.alive_at = Some(self.alive_at.map_or(received_at, |alive_at| alive_at.max(received_at)));
+        self.alive_slot = Some(self.alive_slot.map_or(slot, |alive_slot| alive_slot.max(slot)));
+    }
+
     fn update_bid_price(&mut self, price: f64, quantity: f64) {
         assert!(quantity.is_sign_positive(), "bid quantity must be non-negative but was <{}>", price);
         let price = OrderedFloat(price);
@@ -116,146 +130,483 @@ impl PerpOrderbook {
         self.asks.first_key_value().map(|(k, _)| k.0)
     }
 
+    pub fn best_bid(&self, write_version: u64) -> Option<PriceInfo> {
+        self.get_highest_bid_price().map(|price| PriceInfo {
+            price,
+            quantity: self.bids[&OrderedFloat(price)],
+            write_version,
+        })
+    }
+
+    pub fn best_ask(&self, write_version: u64) -> Option<PriceInfo> {
+        self.get_lowest_ask_price().map(|price| PriceInfo {
+            price,
+            quantity: self.asks[&OrderedFloat(price)],
+            write_version,
+        })
+    }
+
+    // average price when selling base_quantity (ui) into the bids
+    pub fn bid_vwap(&self, base_quantity: f64) -> Result<f64, InsufficientDepth> {
+        vwap(self.bids.iter().rev(), base_quantity)
+    }
+
+    // average price when buying base_quantity (ui) from the asks
+    pub fn ask_vwap(&self, base_quantity: f64) -> Result<f64, InsufficientDepth> {
+        vwap(self.asks.iter(), base_quantity)
+    }
+
+    // replace one side with complete (price, quantity) levels, e.g. from a decoded BookSide account
+    pub fn replace_bids(&mut self, levels: &[(f64, f64)]) {
+        self.bids.clear();
+        for (price, quantity) in levels {
+            self.update_bid_price(*price, *quantity);
+        }
+        self.updated_at = Some(Instant::now());
+    }
+
+    pub fn replace_asks(&mut self, levels: &[(f64, f64)]) {
+        self.asks.clear();
+        for (price, quantity) in levels {
+            self.update_ask_price(*price, *quantity);
+        }
+        self.updated_at = Some(Instant::now());
+    }
+
+    fn clear(&mut self) {
+        self.bids.clear();
+        self.asks.clear();
+        self.updated_at = None;
+        self.slot = None;
+        self.alive_at = None;
+        self.alive_slot = None;
+    }
+
     fn dump(&self) {
         debug!("orderbook bids {:?}", self.bids.iter().map(|(k, v)| (k.0, v)).collect::<Vec<_>>());
         debug!("orderbook asks {:?}", self.asks.iter().map(|(k, v)| (k.0, v)).collect::<Vec<_>>());
     }
 }
 
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum OrderbookMessage {
+    Checkpoint { slot: u64, write_version: u64 },
+    Update { slot: u64, write_version: u64 },
+    // e.g. subscription response
+    Other,
+}
+
+// apply a raw message from the orderbook service to the book
+pub fn apply_orderbook_message(orderbook: &mut PerpOrderbook, plain: &Value) -> anyhow::Result<OrderbookMessage> {
+    // detect checkpoint messages via property bid+ask
+    if plain.get("bids").is_some() && plain.get("asks").is_some() {
+        let checkpoint: OrderbookCheckpoint = serde_json::from_value(plain.clone())?;
+        // a checkpoint replaces the whole book
+        orderbook.clear();
+        for bid in checkpoint.bids {
+            orderbook.update_bid_price(bid[0], bid[1]);
+        }
+        for ask in checkpoint.asks {
+            orderbook.update_ask_price(ask[0], ask[1]);
+        }
+        orderbook.updated_at = Some(Instant::now());
+        orderbook.slot = Some(checkpoint.slot);
+        orderbook.mark_alive(Instant::now(), checkpoint.slot);
+        return Ok(OrderbookMessage::Checkpoint { slot: checkpoint.slot, write_version: checkpoint.write_version });
+    }
+
+    if plain.get("update").is_some() {
+        let update: OrderbookUpdate = serde_json::from_value(plain.clone())?;
+        for level in update.update {
+            match update.side {
+                OrderbookSide::Bid => orderbook.update_bid_price(level[0], level[1]),
+                OrderbookSide::Ask => orderbook.update_ask_price(level[0], level[1]),
+            }
+        }
+        orderbook.updated_at = Some(Instant::now());
+        orderbook.slot = Some(update.slot);
+        orderbook.mark_alive(Instant::now(), update.slot);
+        return Ok(OrderbookMessage::Update { slot: update.slot, write_version: update.write_version });
+    }
+
+    Ok(OrderbookMessage::Other)
+}
+
+// slot and write_version of a raw message without parsing the levels
+pub fn peek_orderbook_message(plain: &Value) -> OrderbookMessage {
+    let (Some(slot), Some(write_version)) = (plain["slot"].as_u64(), plain["write_version"].as_u64()) else {
+        return OrderbookMessage::Other;
+    };
+    if plain.get("bids").is_some() && plain.get("asks").is_some() {
+        OrderbookMessage::Checkpoint { slot, write_version }
+    } else if plain.get("update").is_some() {
+        OrderbookMessage::Update { slot, write_version }
+    } else {
+        OrderbookMessage::Other
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SequenceCheck {
+    InOrder,
+    // no checkpoint yet - updates can't be applied
+    AwaitingCheckpoint,
+    // the book missed or reordered messages and needs a fresh checkpoint
+    OutOfOrder { last: (u64, u64), received: (u64, u64) },
+}
+
+// (slot, write_version) must strictly increase from one checkpoint/update to the next;
+// the feed has no sequence numbers, so missed messages show up as reordering or broadcast lag
+#[derive(Debug, Default)]
+pub struct FeedSequence {
+    last: Option<(u64, u64)>,
+}
+
+impl FeedSequence {
+
+    pub fn check(&mut self, message: OrderbookMessage) -> SequenceCheck {
+        let (received, is_checkpoint) = match message {
+            OrderbookMessage::Checkpoint { slot, write_version } => ((slot, write_version), true),
+            OrderbookMessage::Update { slot, write_version } => ((slot, write_version), false),
+            OrderbookMessage::Other => return SequenceCheck::InOrder,
+        };
+        match self.last {
+            None if !is_checkpoint => SequenceCheck::AwaitingCheckpoint,
+            Some(last) if received <= last => {
+                self.last = None;
+                SequenceCheck::OutOfOrder { last, received }
+            }
+            _ => {
+                self.last = Some(received);
+                SequenceCheck::InOrder
+            }
+        }
+    }
+}
+
+// walk the levels from the top of the book until base_quantity is filled
+fn vwap<'a>(levels: impl Iterator<Item = (&'a OrderedFloat<f64>, &'a f64)>, base_quantity: f64) -> Result<f64, InsufficientDepth> {
+    assert!(base_quantity > 0.0, "quantity must be positive but was <{}>", base_quantity);
+    let mut remaining = base_quantity;
+    let mut notional = 0.0;
+    for (price, quantity) in levels {
+        let take = remaining.min(*quantity);
+        notional += take * price.0;
+        remaining -= take;
+        if remaining <= 0.0 {
+            return Ok(notional / base_quantity);
+        }
+    }
+    Err(InsufficientDepth {
+        requested: base_quantity,
+        available: base_quantity - remaining,
+    })
+}
+
 #[derive(PartialEq, Debug, Clone, Copy)]
 pub struct PriceInfo {
     pub price: f64,
+    // base quantity (ui) available at that price level
+    pub quantity: f64,
     pub write_version: u64,
 }
 
+// best bid and ask are recomputed from the book and published together;
+// returns true if the price or quantity of either changed
+pub async fn publish_top_of_book(orderbook: &PerpOrderbook, write_version: u64,
+                             highest_bid_price: &RwLock<Option<PriceInfo>>, lowest_ask_price: &RwLock<Option<PriceInfo>>) -> bool {
+    let mut highest_bid = highest_bid_price.write().await;
+    let mut lowest_ask = lowest_ask_price.write().await;
+    let (new_bid, new_ask) = (orderbook.best_bid(write_version), orderbook.best_ask(write_version));
+    let level = |price: &Option<PriceInfo>| price.map(|price| (price.price, price.quantity));
+    let changed = level(&highest_bid) != level(&new_bid) || level(&lowest_ask) != level(&new_ask);
+    *highest_bid = new_bid;
+    *lowest_ask = new_ask;
+    changed
+}
+
+// receive time of the feed message which last changed the best bid or ask
+pub type TopOfBookEvents = Arc<watch::Sender<Instant>>;
+
+// the service sends nothing while the book doesn't change - a fresh checkpoint serves as heartbeat;
+// keep below max_leg_skew_ms and max_perp_price_age_ms
+const IDLE_RESUBSCRIBE_AFTER: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum FeedEnd {
+    Closed,
+    // missed or reordered messages - the book is stale until the next checkpoint
+    Resync,
+    // no message within IDLE_RESUBSCRIBE_AFTER
+    Idle,
+}
+
 // requires running "service-mango-orderbook" - see README
 pub async fn listen_perp_market_feed(market_id: &str,
+                                     orderbook: Arc<RwLock<PerpOrderbook>>,
                                      highest_bid_price: Arc<RwLock<Option<PriceInfo>>>,
-                                     lowest_ask_price: Arc<RwLock<Option<PriceInfo>>>) {
-
-    let mut orderbook: PerpOrderbook = PerpOrderbook::default();
-
-    let subscription_request = json!({
-            "command": "subscribe",
-            "marketId": market_id.to_string(),
-        });
-
-    let mut socket = StableWebSocket::new_with_timeout(
-        Url::parse("wss://api.mngo.cloud/orderbook/v1/").unwrap(),
-subscription_request, Duration::from_secs(5)).await.unwrap();
-
-    while let Ok(ws_message) = socket.subscribe_message_channel().recv().await {
-        let WsMessage::Text(plain) = ws_message else { continue; };
-
-        let plain = from_str::<Value>(&plain).expect("Can't parse to JSON");
-
-        // detect checkpoint messages via property bid+ask
-        let is_checkpoint_message = plain.get("bids").is_some() && plain.get("asks").is_some();
-        // detect update messages
-        let is_update_message = plain.get("update").is_some();
-
-        if is_checkpoint_message {
-            let checkpoint: OrderbookCheckpoint = serde_json::from_value(plain.clone()).expect("");
-
-            for bid in checkpoint.bids {
-                let price = OrderstreamPrice {
-                    price: bid[0],
-                    quantity: bid[1],
-                    // TODO derive from slot
-                    approx_timestamp: Instant::now(),
-                };
-                orderbook.update_bid_price(price.price, price.quantity);
-                let mut lock = highest_bid_price.write().await;
-                if let Some(old_highest) = *lock {
-                    if old_highest.write_version > checkpoint.write_version {
-                        // TODO reduce log level
-                        warn!("hmm orderbook update with write_version jumping from {} to {}",
-                            old_highest.write_version, checkpoint.write_version);
-                        // continue;
-                    }
+                                     lowest_ask_price: Arc<RwLock<Option<PriceInfo>>>,
+                                     top_of_book_events: TopOfBookEvents,
+                                     recorder: Option<FeedRecorder>) {
+
+    loop {
+        let subscription_request = json!({
+                "command": "subscribe",
+                "marketId": market_id.to_string(),
+            });
+
+        // the service starts every subscription with a checkpoint
+        let mut socket = StableWebSocket::new_with_timeout(
+            Url::parse("wss://api.mngo.cloud/orderbook/v1/").unwrap(),
+            subscription_request, Duration::from_secs(5)).await.unwrap();
+        let mut messages = socket.subscribe_message_channel();
+        let mut sequence = FeedSequence::default();
+        info!("Subscribed to orderbook feed for market {}, awaiting checkpoint ...", market_id);
+
+        let feed_end = loop {
+            let ws_message = match timeout(IDLE_RESUBSCRIBE_AFTER, messages.recv()).await {
+                Ok(Ok(ws_message)) => ws_message,
+                Ok(Err(RecvError::Lagged(skipped))) => {
+                    warn!("orderbook websocket for market {} lagged, {} messages skipped", market_id, skipped);
+                    break FeedEnd::Resync;
                 }
-                *lock = orderbook.get_highest_bid_price().map(|price| PriceInfo {
-                    price: price,
-                    write_version: checkpoint.write_version,
-                });
+                Ok(Err(RecvError::Closed)) => break FeedEnd::Closed,
+                Err(_idle) => break FeedEnd::Idle,
+            };
+            let WsMessage::Text(plain) = ws_message else { continue; };
+            let received_at = Instant::now();
+
+            if let Some(recorder) = &recorder {
+                recorder.record_orderbook_message(market_id, &plain);
             }
 
-            for ask in checkpoint.asks {
-                let price = OrderstreamPrice {
-                    price: ask[0],
-                    quantity: ask[1],
-                    // TODO derive from slot
-                    approx_timestamp: Instant::now(),
-                };
-                orderbook.update_ask_price(price.price, price.quantity);
-                let mut lock = lowest_ask_price.write().await;
-                if let Some(old_highest) = *lock {
-                    if old_highest.write_version > checkpoint.write_version {
-                        // TODO reduce log level
-                        warn!("hmm orderbook update with write_version jumping from {} to {}",
-                            old_highest.write_version, checkpoint.write_version);
-                        // continue;
-                    }
+            let plain = from_str::<Value>(&plain).expect("Can't parse to JSON");
+
+            match sequence.check(peek_orderbook_message(&plain)) {
+                SequenceCheck::InOrder => {}
+                SequenceCheck::AwaitingCheckpoint => {
+                    trace!("orderbook update for market {} before checkpoint, skipping", market_id);
+                    continue;
+                }
+                SequenceCheck::OutOfOrder { last, received } => {
+                    warn!("orderbook feed for market {} out of sequence: (slot, write_version) {:?} after {:?}",
+                        market_id, received, last);
+                    break FeedEnd::Resync;
                 }
-                *lock = orderbook.get_lowest_ask_price().map(|price| PriceInfo {
-                    price: price,
-                    write_version: checkpoint.write_version,
-                });
             }
-        }
 
-        if is_update_message {
-            let update: OrderbookUpdate = serde_json::from_value(plain.clone()).expect(format!("Can't convert json <{}>", plain).as_str());
-
-            debug!("update({:?}): {:?}", update.slot, update.update);
-            for data in update.update {
-                let price = OrderstreamPrice {
-                    price: data[0],
-                    quantity: data[1],
-                    approx_timestamp: Instant::now(),
-                };
-                if update.side == OrderbookSide::Bid {
-                    orderbook.update_bid_price(price.price, price.quantity);
-                    let mut lock = highest_bid_price.write().await;
-                    if let Some(old_highest) = *lock {
-                        if old_highest.write_version > update.write_version {
-                            // TODO reduce log level
-                            warn!("hmm orderbook update with write_version jumping from {} to {}",
-                                old_highest.write_version, update.write_version);
-                            // continue;
-                        }
-                    }
-                    *lock = Some(PriceInfo {
-                        price: price.price,
-                        write_version: update.write_version,
-                    });
+            // apply the whole message before publishing - levels of one update must not be published one by one
+            let mut book = orderbook.write().await;
+            let write_version = match apply_orderbook_message(&mut book, &plain) {
+                Ok(OrderbookMessage::Checkpoint { slot, write_version }) => {
+                    debug!("checkpoint({:?}) for market {}", slot, market_id);
+                    write_version
+                }
+                Ok(OrderbookMessage::Update { slot, write_version }) => {
+                    debug!("update({:?}) for market {}", slot, market_id);
+                    write_version
                 }
-                if update.side == OrderbookSide::Ask {
-                    orderbook.update_ask_price(price.price, price.quantity);
-                    let mut lock = lowest_ask_price.write().await;
-                    if let Some(old_highest) = *lock {
-                        if old_highest.write_version > update.write_version {
-                            // TODO reduce log level
-                            warn!("hmm orderbook update with write_version jumping from {} to {}",
-                                old_highest.write_version, update.write_version);
-                            // continue;
-                        }
-                    }
-                    *lock = Some(PriceInfo {
-                        price: price.price,
-                        write_version: update.write_version,
-                    });
+                Ok(OrderbookMessage::Other) => continue,
+                Err(err) => {
+                    warn!("Can't apply orderbook message <{}> for market {}: {}", plain, market_id, err);
+                    break FeedEnd::Resync;
                 }
+            };
+            book.dump();
 
-                // TODO remove
-                orderbook.dump();
-                // sell_price_xwrite.send(price).unwrap();
+            if publish_top_of_book(&book, write_version, &highest_bid_price, &lowest_ask_price).await {
+                // fails only without subscribers
+                let _ = top_of_book_events.send(received_at);
             }
+        };
 
+        if feed_end == FeedEnd::Idle {
+            // the book is still valid until the checkpoint of the new subscription replaces it
+            debug!("no orderbook message for market {} within {:?}, resubscribing for a fresh checkpoint", market_id, IDLE_RESUBSCRIBE_AFTER);
+            continue;
         }
 
-    }
+        // the book can't be trusted until the next checkpoint - stop publishing prices to the coordinator
+        warn!("orderbook for market {} is stale", market_id);
+        *highest_bid_price.write().await = None;
+        *lowest_ask_price.write().await = None;
+        orderbook.write().await.clear();
 
-    socket.join().await;
+        if feed_end == FeedEnd::Closed {
+            warn!("Orderbook WebSocket stream for market {} closed", market_id);
+            socket.join().await;
+            return;
+        }
+
+        // dropping the socket ends the old subscription
+        info!("Resubscribing to orderbook feed for market {} to get a fresh checkpoint ...", market_id);
+    }
 }
 
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+    use tokio::sync::RwLock;
+    use crate::services::orderbook_stream::{apply_orderbook_message, FeedSequence, InsufficientDepth, OrderbookMessage, peek_orderbook_message,
+                                            PerpOrderbook, PriceInfo, publish_top_of_book, SequenceCheck};
+
+    fn sample_orderbook() -> PerpOrderbook {
+        let mut orderbook = PerpOrderbook::default();
+        orderbook.update_bid_price(100.0, 1.0);
+        orderbook.update_bid_price(99.0, 2.0);
+        orderbook.update_ask_price(101.0, 1.0);
+        orderbook.update_ask_price(103.0, 1.0);
+        orderbook
+    }
+
+    #[test]
+    fn vwap_within_top_level() {
+        let orderbook = sample_orderbook();
+        assert_eq!(Ok(100.0), orderbook.bid_vwap(0.5));
+        assert_eq!(Ok(101.0), orderbook.ask_vwap(0.5));
+    }
+
+    #[test]
+    fn vwap_walks_levels() {
+        let orderbook = sample_orderbook();
+        // 1.0 @ 100 + 1.0 @ 99
+        assert_eq!(Ok(99.5), orderbook.bid_vwap(2.0));
+        // 1.0 @ 101 + 1.0 @ 103
+        assert_eq!(Ok(102.0), orderbook.ask_vwap(2.0));
+    }
+
+    #[test]
+    fn vwap_insufficient_depth() {
+        let orderbook = sample_orderbook();
+        assert_eq!(Err(InsufficientDepth { requested: 4.0, available: 3.0 }), orderbook.bid_vwap(4.0));
+        assert_eq!(Err(InsufficientDepth { requested: 2.5, available: 2.0 }), orderbook.ask_vwap(2.5));
+    }
+
+    #[test]
+    fn sequence_requires_checkpoint() {
+        let mut sequence = FeedSequence::default();
+        assert_eq!(SequenceCheck::AwaitingCheckpoint, sequence.check(OrderbookMessage::Update { slot: 10, write_version: 100 }));
+        assert_eq!(SequenceCheck::InOrder, sequence.check(OrderbookMessage::Checkpoint { slot: 10, write_version: 101 }));
+        assert_eq!(SequenceCheck::InOrder, sequence.check(OrderbookMessage::Update { slot: 10, write_version: 102 }));
+        assert_eq!(SequenceCheck::InOrder, sequence.check(OrderbookMessage::Update { slot: 11, write_version: 103 }));
+    }
+
+    #[test]
+    fn sequence_detects_reordering() {
+        let mut sequence = FeedSequence::default();
+        sequence.check(OrderbookMessage::Checkpoint { slot: 10, write_version: 100 });
+        sequence.check(OrderbookMessage::Update { slot: 11, write_version: 105 });
+        assert_eq!(SequenceCheck::OutOfOrder { last: (11, 105), received: (11, 104) },
+            sequence.check(OrderbookMessage::Update { slot: 11, write_version: 104 }));
+        // stale until the next checkpoint
+        assert_eq!(SequenceCheck::AwaitingCheckpoint, sequence.check(OrderbookMessage::Update { slot: 12, write_version: 106 }));
+        assert_eq!(SequenceCheck::InOrder, sequence.check(OrderbookMessage::Checkpoint { slot: 12, write_version: 107 }));
+    }
+
+    #[test]
+    fn peek_message_kind() {
+        let checkpoint = json!({"market": "m", "bids": [], "asks": [], "slot": 1, "write_version": 2});
+        let update = json!({"market": "m", "side": "bid", "update": [[100.0, 1.0]], "slot": 3, "write_version": 4});
+        assert_eq!(OrderbookMessage::Checkpoint { slot: 1, write_version: 2 }, peek_orderbook_message(&checkpoint));
+        assert_eq!(OrderbookMessage::Update { slot: 3, write_version: 4 }, peek_orderbook_message(&update));
+        assert_eq!(OrderbookMessage::Other, peek_orderbook_message(&json!({"success": true})));
+    }
+
+    // feeds the raw messages through the book and returns the published best bid and ask
+    async fn published_top_of_book(messages: &[serde_json::Value]) -> (Option<PriceInfo>, Option<PriceInfo>) {
+        let mut orderbook = PerpOrderbook::default();
+        let highest_bid_price = RwLock::new(None);
+        let lowest_ask_price = RwLock::new(None);
+        for message in messages {
+            let write_version = match apply_orderbook_message(&mut orderbook, message).unwrap() {
+                OrderbookMessage::Checkpoint { write_version, .. } | OrderbookMessage::Update { write_version, .. } => write_version,
+                OrderbookMessage::Other => continue,
+            };
+            publish_top_of_book(&orderbook, write_version, &highest_bid_price, &lowest_ask_price).await;
+        }
+        let highest_bid = *highest_bid_price.read().await;
+        let lowest_ask = *lowest_ask_price.read().await;
+        (highest_bid, lowest_ask)
+    }
+
+    fn checkpoint() -> serde_json::Value {
+        json!({"market": "m", "bids": [[100.0, 1.0], [99.0, 2.0]], "asks": [[101.0, 1.5], [102.0, 3.0]], "slot": 1, "write_version": 1})
+    }
+
+    #[tokio::test]
+    async fn top_of_book_from_checkpoint() {
+        let (bid, ask) = published_top_of_book(&[checkpoint()]).await;
+        assert_eq!(Some(PriceInfo { price: 100.0, quantity: 1.0, write_version: 1 }), bid);
+        assert_eq!(Some(PriceInfo { price: 101.0, quantity: 1.5, write_version: 1 }), ask);
+    }
+
+    #[tokio::test]
+    async fn deep_levels_do_not_replace_top_of_book() {
+        let (bid, ask) = published_top_of_book(&[
+            checkpoint(),
+            // new level deep in the book, top level quantity changed
+            json!({"market": "m", "side": "bid", "update": [[95.0, 4.0], [100.0, 0.5]], "slot": 2, "write_version": 2}),
+            json!({"market": "m", "side": "ask", "update": [[110.0, 1.0]], "slot": 2, "write_version": 3}),
+        ]).await;
+        assert_eq!(Some(PriceInfo { price: 100.0, quantity: 0.5, write_version: 3 }), bid);
+        assert_eq!(Some(PriceInfo { price: 101.0, quantity: 1.5, write_version: 3 }), ask);
+    }
+
+    #[tokio::test]
+    async fn removed_top_level_publishes_next_level() {
+        let (bid, ask) = published_top_of_book(&[
+            checkpoint(),
+            json!({"market": "m", "side": "bid", "update": [[100.0, 0.0]], "slot": 2, "write_version": 2}),
+            json!({"market": "m", "side": "ask", "update": [[101.0, 0.0], [100.5, 0.2]], "slot": 3, "write_version": 3}),
+        ]).await;
+        assert_eq!(Some(PriceInfo { price: 99.0, quantity: 2.0, write_version: 3 }), bid);
+        assert_eq!(Some(PriceInfo { price: 100.5, quantity: 0.2, write_version: 3 }), ask);
+    }
+
+    #[tokio::test]
+    async fn empty_side_publishes_none() {
+        let (bid, ask) = published_top_of_book(&[
+            checkpoint(),
+            json!({"market": "m", "side": "ask", "update": [[101.0, 0.0], [102.0, 0.0]], "slot": 2, "write_version": 2}),
+        ]).await;
+        assert_eq!(Some(100.0), bid.map(|bid| bid.price));
+        assert_eq!(None, ask);
+    }
+    #[tokio::test]
+    async fn only_top_of_book_changes_are_reported() {
+        let mut orderbook = PerpOrderbook::default();
+        let highest_bid_price = RwLock::new(None);
+        let lowest_ask_price = RwLock::new(None);
+        apply_orderbook_message(&mut orderbook, &checkpoint()).unwrap();
+        assert!(publish_top_of_book(&orderbook, 1, &highest_bid_price, &lowest_ask_price).await);
+
+        apply_orderbook_message(&mut orderbook,
+            &json!({"market": "m", "side": "bid", "update": [[95.0, 4.0]], "slot": 2, "write_version": 2})).unwrap();
+        assert!(!publish_top_of_book(&orderbook, 2, &highest_bid_price, &lowest_ask_price).await);
+
+        apply_orderbook_message(&mut orderbook,
+            &json!({"market": "m", "side": "ask", "update": [[101.0, 0.5]], "slot": 3, "write_version": 3})).unwrap();
+        assert!(publish_top_of_book(&orderbook, 3, &highest_bid_price, &lowest_ask_price).await);
+    }
+
+    #[test]
+    fn feed_stays_alive_without_book_changes() {
+        let mut orderbook = PerpOrderbook::default();
+        apply_orderbook_message(&mut orderbook, &checkpoint()).unwrap();
+        let updated_at = orderbook.updated_at;
+        assert_eq!(Some(1), orderbook.alive_slot);
+
+        // e.g. an on-chain oracle update while the levels stay the same
+        let later = orderbook.alive_at.unwrap() + std::time::Duration::from_secs(3);
+        orderbook.mark_alive(later, 5);
+        assert_eq!(Some(later), orderbook.alive_at);
+        assert_eq!(Some(5), orderbook.alive_slot);
+        assert_eq!(updated_at, orderbook.updated_at);
+        assert_eq!(Some(1), orderbook.slot);
+
+        // an older notification doesn't move liveness back
+        orderbook.mark_alive(later - std::time::Duration::from_secs(1), 4);
+        assert_eq!(Some(later), orderbook.alive_at);
+        assert_eq!(Some(5), orderbook.alive_slot);
+    }
+}
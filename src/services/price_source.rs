@@ -0,0 +1,207 @@
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use anyhow::anyhow;
+use async_trait::async_trait;
+use tokio::sync::RwLock;
+use crate::services::asset_price_swap::{call_buy_for_size, call_sell_for_size};
+use crate::services::jupiter_quote::{JupiterQuoter, SwapQuote};
+use crate::services::orderbook_stream::{PerpOrderbook, PriceInfo};
+use crate::services::trading_config::TradingConfig;
+
+// side of the taker: Buy = we buy the base asset
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum QuoteSide {
+    Buy,
+    Sell,
+}
+
+impl fmt::Display for QuoteSide {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QuoteSide::Buy => write!(f, "buy"),
+            QuoteSide::Sell => write!(f, "sell"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PriceQuote {
+    pub side: QuoteSide,
+    // average price for the quoted size, e.g. 1900 USDC per ETH
+    pub price: f64,
+    // size the price was quoted for, e.g. 0.01 SOL
+    pub base_quantity_ui: f64,
+    // e.g. 0.0026 = 0.26%
+    pub price_impact_pct: f64,
+    // venue fees in USDC already reflected in the price (e.g. swap route fees)
+    pub fee_ui: f64,
+    // when the underlying market data was observed (not when the quote was computed)
+    pub observed_at: Instant,
+    // slot of the underlying market data if known
+    pub slot: Option<u64>,
+    // swap venues: route the price was quoted for
+    pub swap_quote: Option<Arc<SwapQuote>>,
+}
+
+impl PriceQuote {
+    pub fn age(&self) -> Duration {
+        self.observed_at.elapsed()
+    }
+}
+
+// a venue which can price a trade of a given size
+#[async_trait]
+pub trait PriceSource: Send + Sync {
+    fn name(&self) -> &str;
+    async fn quote(&self, side: QuoteSide, base_quantity_ui: f64) -> anyhow::Result<PriceQuote>;
+}
+
+// swap router quotes (one request per quote)
+pub struct JupiterPriceSource {
+    quoter: Arc<JupiterQuoter>,
+    trading_config: Arc<TradingConfig>,
+}
+
+impl JupiterPriceSource {
+    pub fn new(quoter: Arc<JupiterQuoter>, trading_config: Arc<TradingConfig>) -> Self {
+        JupiterPriceSource { quoter, trading_config }
+    }
+}
+
+#[async_trait]
+impl PriceSource for JupiterPriceSource {
+    fn name(&self) -> &str {
+        "jupiter"
+    }
+
+    async fn quote(&self, side: QuoteSide, base_quantity_ui: f64) -> anyhow::Result<PriceQuote> {
+        let quote = match side {
+            QuoteSide::Buy => {
+                let price = call_buy_for_size(&self.quoter, &self.trading_config, base_quantity_ui).await?;
+                PriceQuote {
+                    side,
+                    price: price.price,
+                    base_quantity_ui: price.base_quantity_ui,
+                    price_impact_pct: price.price_impact_pct,
+                    fee_ui: price.route_fee_ui,
+                    observed_at: price.approx_timestamp,
+                    slot: price.quote.context_slot,
+                    swap_quote: Some(price.quote),
+                }
+            }
+            QuoteSide::Sell => {
+                let price = call_sell_for_size(&self.quoter, &self.trading_config, base_quantity_ui).await?;
+                PriceQuote {
+                    side,
+                    price: price.price,
+                    base_quantity_ui: price.base_quantity_ui,
+                    price_impact_pct: price.price_impact_pct,
+                    fee_ui: price.route_fee_ui,
+                    observed_at: price.approx_timestamp,
+                    slot: price.quote.context_slot,
+                    swap_quote: Some(price.quote),
+                }
+            }
+        };
+        Ok(quote)
+    }
+}
+
+// perp orderbook maintained by one of the orderbook listeners; buying walks up the asks, selling walks down the bids
+pub struct OrderbookPriceSource {
+    orderbook: Arc<RwLock<PerpOrderbook>>,
+    highest_bid_price: Arc<RwLock<Option<PriceInfo>>>,
+    lowest_ask_price: Arc<RwLock<Option<PriceInfo>>>,
+}
+
+impl OrderbookPriceSource {
+    pub fn new(orderbook: Arc<RwLock<PerpOrderbook>>,
+               highest_bid_price: Arc<RwLock<Option<PriceInfo>>>,
+               lowest_ask_price: Arc<RwLock<Option<PriceInfo>>>) -> Self {
+        OrderbookPriceSource { orderbook, highest_bid_price, lowest_ask_price }
+    }
+}
+
+#[async_trait]
+impl PriceSource for OrderbookPriceSource {
+    fn name(&self) -> &str {
+        "orderbook"
+    }
+
+    async fn quote(&self, side: QuoteSide, base_quantity_ui: f64) -> anyhow::Result<PriceQuote> {
+        // nothing is published while the book is stale
+        let top_of_book = match side {
+            QuoteSide::Buy => *self.lowest_ask_price.read().await,
+            QuoteSide::Sell => *self.highest_bid_price.read().await,
+        };
+        let Some(top_of_book) = top_of_book else {
+            return Err(anyhow!("no {} side in orderbook", if side == QuoteSide::Buy { "ask" } else { "bid" }));
+        };
+
+        let orderbook = self.orderbook.read().await;
+        // a quiet book is as current as the last message or heartbeat of its feed
+        let Some(observed_at) = orderbook.alive_at else {
+            return Err(anyhow!("orderbook feed never received a message"));
+        };
+        let vwap = match side {
+            QuoteSide::Buy => orderbook.ask_vwap(base_quantity_ui),
+            QuoteSide::Sell => orderbook.bid_vwap(base_quantity_ui),
+        }.map_err(|depth| anyhow!("insufficient perp {} depth ({} of {})", side, depth.available, depth.requested))?;
+
+        Ok(PriceQuote {
+            side,
+            price: vwap,
+            base_quantity_ui,
+            price_impact_pct: (vwap - top_of_book.price).abs() / top_of_book.price,
+            fee_ui: 0.0,
+            observed_at,
+            slot: orderbook.alive_slot,
+            swap_quote: None,
+        })
+    }
+}
+
+// fixed prices set by the caller, e.g. for offline tests
+#[cfg(test)]
+#[derive(Default)]
+pub struct MockPriceSource {
+    prices: std::sync::Mutex<std::collections::HashMap<QuoteSide, f64>>,
+}
+
+#[cfg(test)]
+impl MockPriceSource {
+    pub fn with_prices(buy: f64, sell: f64) -> Self {
+        let source = MockPriceSource::default();
+        source.set_price(QuoteSide::Buy, buy);
+        source.set_price(QuoteSide::Sell, sell);
+        source
+    }
+
+    pub fn set_price(&self, side: QuoteSide, price: f64) {
+        self.prices.lock().unwrap().insert(side, price);
+    }
+}
+
+#[cfg(test)]
+#[async_trait]
+impl PriceSource for MockPriceSource {
+    fn name(&self) -> &str {
+        "mock"
+    }
+
+    async fn quote(&self, side: QuoteSide, base_quantity_ui: f64) -> anyhow::Result<PriceQuote> {
+        let price = *self.prices.lock().unwrap().get(&side)
+            .ok_or_else(|| anyhow!("no mock {} price", side))?;
+        Ok(PriceQuote {
+            side,
+            price,
+            base_quantity_ui,
+            price_impact_pct: 0.0,
+            fee_ui: 0.0,
+            observed_at: Instant::now(),
+            slot: None,
+            swap_quote: None,
+        })
+    }
+}
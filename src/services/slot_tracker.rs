@@ -0,0 +1,72 @@
+use log::*;
+use solana_client::nonblocking::rpc_client::RpcClient;
+use solana_sdk::{clock::DEFAULT_MS_PER_SLOT, commitment_config::CommitmentConfig};
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::time::{sleep, timeout};
+use crate::supervisor::{RESTART_WITH_BACKOFF, Supervisor};
+
+const RETRY: Duration = Duration::from_millis(DEFAULT_MS_PER_SLOT);
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+// latest processed slot of the cluster, 0 until the first successful poll
+#[derive(Default)]
+pub struct SlotTracker {
+    slot: AtomicU64,
+}
+
+impl SlotTracker {
+    pub fn current_slot(&self) -> Option<u64> {
+        match self.slot.load(Ordering::Relaxed) {
+            0 => None,
+            slot => Some(slot),
+        }
+    }
+
+    fn update(&self, slot: u64) {
+        self.slot.fetch_max(slot, Ordering::Relaxed);
+    }
+}
+
+async fn poll_loop(client: Arc<RpcClient>, tracker: Arc<SlotTracker>) {
+    loop {
+        match timeout(TIMEOUT, client.get_slot()).await {
+            Ok(Ok(slot)) => {
+                trace!("slot update {}", slot);
+                tracker.update(slot);
+            }
+            Ok(Err(e)) => {
+                error!("error reading slot err={e:?}. sleep for {TIMEOUT:?}");
+                sleep(TIMEOUT - RETRY).await;
+            }
+            Err(e) => {
+                error!("timeout reading slot err={e:?}");
+            }
+        }
+
+        // poll about once per slot
+        sleep(RETRY).await;
+    }
+}
+
+pub fn start_slot_tracker(rpc_url: String, supervisor: &Supervisor) -> Arc<SlotTracker> {
+    let rpc_client = Arc::new(RpcClient::new_with_commitment(
+        rpc_url,
+        CommitmentConfig::processed(),
+    ));
+    let tracker = Arc::new(SlotTracker::default());
+
+    // launch task
+    {
+        let tracker_c = tracker.clone();
+        supervisor.supervise("slot tracker", RESTART_WITH_BACKOFF,
+            move || poll_loop(rpc_client.clone(), tracker_c.clone()));
+    }
+
+    tracker
+}
@@ -6,58 +6,152 @@ use crate::numerics::{native_amount, native_amount2, native_amount_to_lot, quote
 use std::future::Future;
 use std::ops::Deref;
 use std::str::FromStr;
-use anyhow::anyhow;
+use std::time::Duration;
+use anyhow::{anyhow, bail, Context};
 use log::debug;
+use mango_v4::state::{MangoAccountValue, TokenIndex, QUOTE_DECIMALS, QUOTE_TOKEN_INDEX};
+use mango_v4_client::jupiter;
 use serde::{Deserialize, Serialize};
 use solana_sdk::signature::Signature;
-use crate::MangoClientRef;
-use crate::services::trading_config;
-use crate::services::trading_config::TOKEN_NAME;
+use crate::{CacheControl, MangoClientRef};
+use crate::services::asset_price_swap::quote_swap_leg;
+use crate::services::jupiter_quote::{JupiterQuoter, SwapQuote};
+use crate::services::price_source::QuoteSide;
+use crate::services::trading_config::TradingConfig;
+use crate::services::transactions::TransactionConfirmer;
 
-// bps
-const SLIPPAGE: u64 = 5;
+// rounding and interest accrued on the token position while the swap settles
+const BALANCE_DELTA_MARGIN_BPS: u64 = 5;
 
-pub async fn swap_sell_asset(mango_client: Arc<MangoClientRef>, amount: f64) -> anyhow::Result<Signature> {
-    let market_index = mango_client.context.token_indexes_by_name.get(trading_config::TOKEN_NAME).unwrap();
-    let market = mango_client.context.tokens.get(market_index).unwrap();
+// sell the base token for USDC (token->USDC) in the configured sell mode
+// only return sig, caller must check for progress/confirmation
+pub async fn swap_sell_asset(mango_client: Arc<MangoClientRef>, quoter: &JupiterQuoter, trading_config: &TradingConfig, amount: f64) -> anyhow::Result<Signature> {
+    let quote = quote_swap_leg(quoter, trading_config, QuoteSide::Sell, amount).await?;
+    debug!("swap order sell {} with in {} out {}", quote.swap_mode, quote.in_amount, quote.out_amount);
+    send_swap_route(mango_client, &quote).await
+}
+
+// buy the base token with USDC (USDC->token) in the configured buy mode
+// only return sig, caller must check for progress/confirmation
+pub async fn swap_buy_asset(mango_client: Arc<MangoClientRef>, quoter: &JupiterQuoter, trading_config: &TradingConfig, amount: f64) -> anyhow::Result<Signature> {
+    let quote = quote_swap_leg(quoter, trading_config, QuoteSide::Buy, amount).await?;
+    debug!("swap order buy {} with in {} out {}", quote.swap_mode, quote.in_amount, quote.out_amount);
+    // Error Message: Slippage tolerance exceeded
+    send_swap_route(mango_client, &quote).await
+}
+
+// execute exactly the route the trade decision was based on; a stale quote is refreshed first and
+// the swap is aborted if the refreshed route is worse than the quoted one
+pub async fn swap_quoted_route(mango_client: Arc<MangoClientRef>, quoter: &JupiterQuoter, trading_config: &TradingConfig,
+                               quote: &SwapQuote) -> anyhow::Result<Signature> {
+    let max_age = Duration::from_millis(trading_config.swap_quote_max_age_ms);
+    let route = if quote.age() <= max_age {
+        quote.clone()
+    } else {
+        let requote = quoter.quote(quote.input_mint, quote.output_mint, quote.requested_amount(), quote.swap_mode,
+            trading_config.swap_slippage_bps, true).await?;
+        let deviation_bps = quote.deviation_bps(&requote);
+        debug!("quote aged {:?}, refreshed route deviates {:.2} bps (in {} -> {}, out {} -> {})", quote.age(), deviation_bps,
+            quote.in_amount, requote.in_amount, quote.out_amount, requote.out_amount);
+        if deviation_bps > trading_config.swap_quote_max_deviation_bps as f64 {
+            bail!("refreshed swap route deviates {:.2} bps from the quoted one (max {} bps) - aborting swap",
+                deviation_bps, trading_config.swap_quote_max_deviation_bps);
+        }
+        requote
+    };
 
-    let order_size_sell = native_amount2(market.decimals as u32, amount);
+    send_swap_route(mango_client, &route).await
+}
 
-    debug!("swap order sell with size {:?}", order_size_sell);
-    let sig_sell = mango_client.jupiter_v6().swap(
-        Pubkey::from_str(trading_config::MINT_ADDRESS_INPUT).unwrap(),
-        Pubkey::from_str(trading_config::MINT_ADDRESS_OUTPUT).unwrap(),
-        order_size_sell,
-        SLIPPAGE, // TODO 0.01%, 100=1% make configurable
-        true
-    ).await;
+async fn send_swap_route(mango_client: Arc<MangoClientRef>, route: &SwapQuote) -> anyhow::Result<Signature> {
+    let quote_response: jupiter::v6::QuoteResponse = serde_json::from_value(route.response.clone())
+        .context("Can't convert jupiter quote response")?;
+    let tx_builder = mango_client.jupiter_v6().prepare_swap_transaction(&quote_response).await?;
+    let sig = tx_builder.send(&mango_client.client).await;
 
-    debug!("tx-sig swap sell: {:?}", sig_sell);
+    debug!("tx-sig swap {} {} -> {}: {:?}", route.swap_mode, route.input_mint, route.output_mint, sig);
 
-    sig_sell
+    sig
 }
 
-// only return sig, caller must check for progress/confirmation
-pub async fn swap_buy_asset(mango_client: Arc<MangoClientRef>, amount: f64) -> anyhow::Result<Signature> {
-    let market_index = mango_client.context.token_indexes_by_name.get(TOKEN_NAME).unwrap();
-    let market = mango_client.context.tokens.get(market_index).unwrap();
+// base token position of the mango account (ui), where the swaps settle
+// note: invalidates mango account cache
+pub async fn token_position_ui(mango_client: &MangoClientRef, trading_config: &TradingConfig) -> anyhow::Result<f64> {
+    Ok(swap_positions_ui(mango_client, trading_config).await?.0)
+}
 
-    let order_size_buy = native_amount2(market.decimals as u32, amount);
+// base and quote (USDC) token positions of the mango account (ui) from the same account snapshot
+// note: invalidates mango account cache
+pub async fn swap_positions_ui(mango_client: &MangoClientRef, trading_config: &TradingConfig) -> anyhow::Result<(f64, f64)> {
+    mango_client.clear_account_cache();
+    let mango_account = mango_client.mango_account().await?;
+    swap_positions_ui_of(mango_client, trading_config, &mango_account).await
+}
 
-    debug!("swap order buy with size {:?}", order_size_buy);
-    let sig_buy = mango_client.jupiter_v6().swap(
-        Pubkey::from_str(trading_config::MINT_ADDRESS_INPUT).unwrap(),
-        Pubkey::from_str(trading_config::MINT_ADDRESS_OUTPUT).unwrap(),
-        order_size_buy,
-        SLIPPAGE, // TODO 0.1%, 100=1% make configurable
-        // JupiterSwapMode::ExactOut
-        true
-    ).await;
+// same as of the slot a transaction was confirmed in
+pub async fn swap_positions_ui_at_slot(mango_client: &MangoClientRef, confirmer: &TransactionConfirmer,
+                                       trading_config: &TradingConfig, slot: u64) -> anyhow::Result<(f64, f64)> {
+    let mango_account = confirmer.mango_account_at_slot(&mango_client.mango_account_address, slot).await?;
+    swap_positions_ui_of(mango_client, trading_config, &mango_account).await
+}
 
-    debug!("tx-sig swap buy: {:?}", sig_buy);
-    // TODO return sig
+async fn swap_positions_ui_of(mango_client: &MangoClientRef, trading_config: &TradingConfig,
+                              mango_account: &MangoAccountValue) -> anyhow::Result<(f64, f64)> {
+    let Some(token_index) = mango_client.context.token_indexes_by_name.get(&trading_config.token_name) else {
+        bail!("token <{}> not found in mango group", trading_config.token_name);
+    };
+    let base_native = token_position_native(mango_client, mango_account, *token_index).await?;
+    let quote_native = token_position_native(mango_client, mango_account, QUOTE_TOKEN_INDEX).await?;
+    Ok((base_native / 10f64.powi(trading_config.base_decimals as i32),
+        quote_native / 10f64.powi(QUOTE_DECIMALS as i32)))
+}
 
-    // Error Message: Slippage tolerance exceeded
-    sig_buy
+async fn token_position_native(mango_client: &MangoClientRef, mango_account: &MangoAccountValue, token_index: TokenIndex) -> anyhow::Result<f64> {
+    let Ok(position) = mango_account.token_position(token_index) else {
+        // no position yet
+        return Ok(0.0);
+    };
+    let bank = mango_client.first_bank(token_index).await?;
+    Ok(position.native(&bank).to_num())
 }
 
+// the swap must have moved the token position in the direction of the trade by the traded size;
+// the amount not fixed by the swap mode may deviate by the slippage tolerance
+pub fn check_swap_balance_delta(trading_config: &TradingConfig, side: QuoteSide, base_quantity_ui: f64, delta_ui: f64) -> anyhow::Result<()> {
+    let expected = match side {
+        QuoteSide::Buy => base_quantity_ui,
+        QuoteSide::Sell => -base_quantity_ui,
+    };
+    if delta_ui == 0.0 || delta_ui.signum() != expected.signum() {
+        bail!("swap {} moved token position by {:.9} - expected {:.9}", side, delta_ui, expected);
+    }
+    let tolerance = base_quantity_ui * (trading_config.swap_slippage_bps + BALANCE_DELTA_MARGIN_BPS) as f64 / 10_000.0;
+    if (delta_ui - expected).abs() > tolerance {
+        bail!("swap {} moved token position by {:.9} - expected {:.9} +/- {:.9}", side, delta_ui, expected, tolerance);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::services::trading_config::test_trading_config;
+    use super::*;
+
+    #[test]
+    fn sell_must_reduce_token_position() {
+        let trading_config = test_trading_config();
+        assert!(check_swap_balance_delta(&trading_config, QuoteSide::Sell, 1.0, -1.0).is_ok());
+        // bought instead of sold
+        assert!(check_swap_balance_delta(&trading_config, QuoteSide::Sell, 1.0, 1.0).is_err());
+        assert!(check_swap_balance_delta(&trading_config, QuoteSide::Sell, 1.0, 0.0).is_err());
+    }
+
+    #[test]
+    fn buy_size_within_tolerance() {
+        let trading_config = test_trading_config();
+        // 5 bps slippage + margin
+        assert!(check_swap_balance_delta(&trading_config, QuoteSide::Buy, 1.0, 0.9996).is_ok());
+        assert!(check_swap_balance_delta(&trading_config, QuoteSide::Buy, 1.0, 0.99).is_err());
+        assert!(check_swap_balance_delta(&trading_config, QuoteSide::Buy, 1.0, 2.0).is_err());
+    }
+}
@@ -1,32 +1,547 @@
+use std::collections::HashSet;
+use std::fs;
+use std::str::FromStr;
+use anyhow::{bail, Context};
+use log::info;
+use mango_v4_client::MangoGroupContext;
+use serde::Deserialize;
+use solana_sdk::pubkey::Pubkey;
+use crate::services::jupiter_quote::{SwapMode, DEFAULT_JUPITER_V6_URL};
 
-const MARKET_ETH_PERP: &str = "Fgh9JSZ2qfSjCw9RPJ85W2xbihsp2muLvfRztzoVR7f1";
-const MARKET_SOL_PERP: &str = "ESdnpnNLgTkBCZRuTJkZLi5wKEZ2z47SG3PJrhundSQ2";
-
-const MINT_ADDRESS_USDC: &str = "EPjFWdd5AufqSSqeM2qN1xzybapC8G4wEGGkZwyTDt1v";
-const MINT_ADDRESS_ETH: &str = "7vfCXTUXx5WJV5JADk17DUJ4ksgau7utNKj4b963voxs";
-const MINT_ADDRESS_SOL: &str = "So11111111111111111111111111111111111111112";
-
-
-// ETH
-// 1 bps = 0.0001 = 0.01%
-// pub const PROFIT_THRESHOLD: f64 = 0.005; // 50 bps
-// pub const BASE_QTY_UI: f64 = 0.0001; // 0.18 USD
-// pub const PERP_ALLOWANCE_THRESHOLD_BASE_UI: f64 = 0.02;
-// pub const BASE_DECIMALS: u8 = 8;
-// pub const MARKET: &str = MARKET_ETH_PERP;
-// pub const PERP_MARKET_NAME: &'static str = "ETH-PERP";
-// pub const TOKEN_NAME: &'static str = "ETH (Portal)";
-// pub const MINT_ADDRESS_INPUT: &str = MINT_ADDRESS_USDC;
-// pub const MINT_ADDRESS_OUTPUT: &str = MINT_ADDRESS_ETH;
-
-// SOL
-// 1 bps = 0.0001 = 0.01%
-pub const PROFIT_THRESHOLD: f64 = 0.002; // 20 bps
-pub const BASE_QTY_UI: f64 = 0.01; // .22 USD
-pub const PERP_ALLOWANCE_THRESHOLD_BASE_UI: f64 = 1.1;
-pub const BASE_DECIMALS: u8 = 9;
-pub const MARKET: &str = MARKET_SOL_PERP;
-pub const PERP_MARKET_NAME: &'static str = "SOL-PERP";
-pub const TOKEN_NAME: &'static str = "SOL";
-pub const MINT_ADDRESS_INPUT: &str = MINT_ADDRESS_USDC;
-pub const MINT_ADDRESS_OUTPUT: &str = MINT_ADDRESS_SOL;
+// see config/sol-perp.toml, config/eth-perp.toml and config/multi.toml
+#[derive(Deserialize, Debug, Clone)]
+pub struct BotConfig {
+    // account-wide: maximum number of trade sequences running at the same time across all pairs
+    #[serde(default = "default_max_concurrent_trades")]
+    pub max_concurrent_trades: usize,
+    // priority fee, see TransactionBuilderConfig
+    #[serde(default = "default_compute_unit_price_micro_lamports")]
+    pub compute_unit_price_micro_lamports: u64,
+    // compute units requested by one transaction (used to estimate the priority fee)
+    #[serde(default = "default_estimated_compute_units_per_tx")]
+    pub estimated_compute_units_per_tx: u64,
+    // used to convert transaction fees (lamports) to USDC
+    pub sol_price_ui_estimate: f64,
+    // where the perp orderbook comes from
+    #[serde(default)]
+    pub orderbook_source: OrderbookSource,
+    // quote API used for pricing swaps; the swap transaction for the quoted route is built by the mango client's own Jupiter endpoint
+    #[serde(default = "default_jupiter_v6_url")]
+    pub jupiter_v6_url: String,
+    // init health (USDC) which must remain after both legs of a trade (on top of collateral reserved for other pairs)
+    #[serde(default)]
+    pub init_health_buffer_ui: f64,
+    // limits which halt trading until reset, see [circuit_breaker] in config/multi.toml
+    #[serde(default)]
+    pub circuit_breaker: CircuitBreakerConfig,
+    // on SIGINT/SIGTERM running trade sequences get this long to finish or unwind
+    #[serde(default = "default_shutdown_timeout_secs")]
+    pub shutdown_timeout_secs: u64,
+    #[serde(rename = "pair")]
+    pub pairs: Vec<TradingConfig>,
+}
+
+#[derive(Deserialize, Debug, Copy, Clone, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum OrderbookSource {
+    // hosted service-mango-orderbook (wss://api.mngo.cloud/orderbook/v1/)
+    #[default]
+    MangoService,
+    // bids/asks accounts via accountSubscribe on the RPC node
+    Onchain,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct CircuitBreakerConfig {
+    // failed leg transactions (incl. retries) in a row
+    #[serde(default = "default_max_consecutive_failed_transactions")]
+    pub max_consecutive_failed_transactions: u32,
+    // realized loss (USDC) of finished trade sequences within the loss window
+    #[serde(default = "default_max_realized_loss_ui")]
+    pub max_realized_loss_ui: f64,
+    #[serde(default = "default_loss_window_secs")]
+    pub loss_window_secs: u64,
+    // trade sequences started within the last hour
+    #[serde(default = "default_max_trades_per_hour")]
+    pub max_trades_per_hour: usize,
+    // max time without a fresh observation from any price feed
+    #[serde(default = "default_max_price_feed_silence_ms")]
+    pub max_price_feed_silence_ms: u64,
+    // share of failed RPC requests within the RPC window, only evaluated with enough requests
+    #[serde(default = "default_max_rpc_error_rate")]
+    pub max_rpc_error_rate: f64,
+    #[serde(default = "default_rpc_error_window_secs")]
+    pub rpc_error_window_secs: u64,
+    #[serde(default = "default_min_rpc_samples")]
+    pub min_rpc_samples: usize,
+    // trading halts while this file exists
+    #[serde(default)]
+    pub kill_switch_file: Option<String>,
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        CircuitBreakerConfig {
+            max_consecutive_failed_transactions: default_max_consecutive_failed_transactions(),
+            max_realized_loss_ui: default_max_realized_loss_ui(),
+            loss_window_secs: default_loss_window_secs(),
+            max_trades_per_hour: default_max_trades_per_hour(),
+            max_price_feed_silence_ms: default_max_price_feed_silence_ms(),
+            max_rpc_error_rate: default_max_rpc_error_rate(),
+            rpc_error_window_secs: default_rpc_error_window_secs(),
+            min_rpc_samples: default_min_rpc_samples(),
+            kill_switch_file: None,
+        }
+    }
+}
+
+fn default_max_consecutive_failed_transactions() -> u32 {
+    5
+}
+
+fn default_max_realized_loss_ui() -> f64 {
+    50.0
+}
+
+fn default_loss_window_secs() -> u64 {
+    24 * 60 * 60
+}
+
+fn default_max_trades_per_hour() -> usize {
+    60
+}
+
+fn default_max_price_feed_silence_ms() -> u64 {
+    60_000
+}
+
+fn default_max_rpc_error_rate() -> f64 {
+    0.5
+}
+
+fn default_rpc_error_window_secs() -> u64 {
+    300
+}
+
+fn default_min_rpc_samples() -> usize {
+    20
+}
+
+fn default_jupiter_v6_url() -> String {
+    DEFAULT_JUPITER_V6_URL.to_string()
+}
+
+fn default_max_concurrent_trades() -> usize {
+    1
+}
+
+fn default_shutdown_timeout_secs() -> u64 {
+    60
+}
+
+fn default_compute_unit_price_micro_lamports() -> u64 {
+    1
+}
+
+fn default_estimated_compute_units_per_tx() -> u64 {
+    400_000
+}
+
+impl BotConfig {
+
+    pub fn load_from_file(path: &str) -> anyhow::Result<BotConfig> {
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Can't read trading config file <{}>", path))?;
+        let config: BotConfig = toml::from_str(&content)
+            .with_context(|| format!("Can't parse trading config file <{}>", path))?;
+        config.validate()
+            .with_context(|| format!("Invalid trading config file <{}>", path))?;
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.pairs.is_empty() {
+            bail!("at least one [[pair]] must be configured");
+        }
+        if self.max_concurrent_trades == 0 {
+            bail!("max_concurrent_trades must be at least 1");
+        }
+        if !(self.sol_price_ui_estimate > 0.0) {
+            bail!("sol_price_ui_estimate must be positive but was <{}>", self.sol_price_ui_estimate);
+        }
+        if self.circuit_breaker.max_consecutive_failed_transactions == 0 || self.circuit_breaker.max_trades_per_hour == 0 {
+            bail!("circuit_breaker: max_consecutive_failed_transactions and max_trades_per_hour must be at least 1");
+        }
+        if !(self.circuit_breaker.max_realized_loss_ui > 0.0) {
+            bail!("circuit_breaker: max_realized_loss_ui must be positive but was <{}>", self.circuit_breaker.max_realized_loss_ui);
+        }
+        if !(self.circuit_breaker.max_rpc_error_rate > 0.0 && self.circuit_breaker.max_rpc_error_rate <= 1.0) {
+            bail!("circuit_breaker: max_rpc_error_rate must be in (0, 1] but was <{}>", self.circuit_breaker.max_rpc_error_rate);
+        }
+        if !(self.init_health_buffer_ui >= 0.0) {
+            bail!("init_health_buffer_ui must not be negative but was <{}>", self.init_health_buffer_ui);
+        }
+        let mut perp_markets = HashSet::new();
+        let mut tokens = HashSet::new();
+        for pair in &self.pairs {
+            pair.validate()
+                .with_context(|| format!("Invalid pair <{}>", pair.pair_name()))?;
+            if !perp_markets.insert(pair.perp_market_name.as_str()) {
+                bail!("perp market <{}> is configured more than once", pair.perp_market_name);
+            }
+            if !tokens.insert(pair.token_name.as_str()) {
+                bail!("token <{}> is configured more than once", pair.token_name);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn validate_against_group(&self, context: &MangoGroupContext) -> anyhow::Result<()> {
+        for pair in &self.pairs {
+            pair.validate_against_group(context)?;
+        }
+        Ok(())
+    }
+
+    // collateral (init health in USDC) set aside for all pairs except the given one
+    pub fn collateral_reserved_by_others(&self, perp_market_name: &str) -> f64 {
+        self.pairs.iter()
+            .filter(|pair| pair.perp_market_name != perp_market_name)
+            .map(|pair| pair.collateral_reserve_ui)
+            .sum()
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct TradingConfig {
+    // 1 bps = 0.0001 = 0.01%
+    pub profit_threshold: f64,
+    // order size used for both legs, e.g. 0.01 SOL
+    pub base_qty_ui: f64,
+    pub perp_allowance_threshold_base_ui: f64,
+    pub base_decimals: u8,
+    // perp market account
+    pub market: String,
+    // e.g. "SOL-PERP"
+    pub perp_market_name: String,
+    // e.g. "SOL" or "ETH (Portal)"
+    pub token_name: String,
+    // quote token (USDC)
+    pub mint_address_input: String,
+    // base token
+    pub mint_address_output: String,
+    // init health (USDC) other pairs must leave untouched so this pair can still trade
+    #[serde(default)]
+    pub collateral_reserve_ui: f64,
+    // attempts for the hedging leg (and the unwind) before giving up; a leg which confirmed is never sent again
+    #[serde(default = "default_leg_retry_attempts")]
+    pub leg_retry_attempts: u32,
+    #[serde(default = "default_leg_retry_delay_ms")]
+    pub leg_retry_delay_ms: u64,
+    // max time to wait for the perp fill to show up on the fills feed before the perp position is checked instead
+    #[serde(default = "default_fill_timeout_ms")]
+    pub fill_timeout_ms: u64,
+    // optional additional sizes (base ui) quoted on the swap router to log the price impact
+    #[serde(default)]
+    pub quote_ladder_ui: Vec<f64>,
+    // swap quotes for the trade size are requested this often (per side)
+    #[serde(default = "default_swap_quote_interval_ms")]
+    pub swap_quote_interval_ms: u64,
+    // slippage tolerance for jupiter swaps; 1 bps = 0.01%
+    #[serde(default = "default_swap_slippage_bps")]
+    pub swap_slippage_bps: u64,
+    // a quote older than this is refreshed before the swap is executed
+    #[serde(default = "default_swap_quote_max_age_ms")]
+    pub swap_quote_max_age_ms: u64,
+    // maximum deterioration of the refreshed route vs. the quoted one before the swap is aborted
+    #[serde(default = "default_swap_quote_max_deviation_bps")]
+    pub swap_quote_max_deviation_bps: u64,
+    // exact_out: receive exactly the trade size; exact_in: spend the USDC the trade size is quoted at
+    #[serde(default = "default_swap_buy_mode")]
+    pub swap_buy_mode: SwapMode,
+    // exact_in: sell exactly the trade size; exact_out: receive the USDC the trade size is quoted at
+    #[serde(default = "default_swap_sell_mode")]
+    pub swap_sell_mode: SwapMode,
+    // prices older than this are not traded on
+    #[serde(default = "default_max_swap_price_age_ms")]
+    pub max_swap_price_age_ms: u64,
+    // perp prices are as old as the last message or heartbeat of the orderbook feed, not the last book change
+    #[serde(default = "default_max_perp_price_age_ms")]
+    pub max_perp_price_age_ms: u64,
+    // slot the orderbook feed was last current at behind the cluster slot (~400ms per slot)
+    #[serde(default = "default_max_perp_slot_lag")]
+    pub max_perp_slot_lag: u64,
+    // max time between the observations of the swap and the perp price
+    #[serde(default = "default_max_leg_skew_ms")]
+    pub max_leg_skew_ms: u64,
+    // inventory manager: reduce the net base exposure (perp + spot) once it leaves this band; disabled if not set
+    #[serde(default)]
+    pub inventory_band_base_ui: Option<f64>,
+    // spot token balance held on purpose (e.g. as collateral), not counted as exposure
+    #[serde(default)]
+    pub inventory_spot_baseline_ui: f64,
+    // max cost (USDC) of one reducing order: price impact vs. perp mid, fees and slippage tolerance
+    #[serde(default = "default_inventory_max_cost_ui")]
+    pub inventory_max_cost_ui: f64,
+    #[serde(default = "default_inventory_check_interval_ms")]
+    pub inventory_check_interval_ms: u64,
+}
+
+fn default_leg_retry_attempts() -> u32 {
+    3
+}
+
+fn default_leg_retry_delay_ms() -> u64 {
+    500
+}
+
+fn default_fill_timeout_ms() -> u64 {
+    10_000
+}
+
+fn default_swap_slippage_bps() -> u64 {
+    5
+}
+
+fn default_swap_quote_max_age_ms() -> u64 {
+    2_000
+}
+
+fn default_swap_quote_interval_ms() -> u64 {
+    2_000
+}
+
+fn default_swap_quote_max_deviation_bps() -> u64 {
+    10
+}
+
+fn default_max_swap_price_age_ms() -> u64 {
+    3_000
+}
+
+fn default_max_perp_price_age_ms() -> u64 {
+    10_000
+}
+
+fn default_max_perp_slot_lag() -> u64 {
+    25
+}
+
+fn default_max_leg_skew_ms() -> u64 {
+    2_000
+}
+
+fn default_inventory_max_cost_ui() -> f64 {
+    1.0
+}
+
+fn default_inventory_check_interval_ms() -> u64 {
+    30_000
+}
+
+fn default_swap_buy_mode() -> SwapMode {
+    SwapMode::ExactOut
+}
+
+fn default_swap_sell_mode() -> SwapMode {
+    SwapMode::ExactIn
+}
+
+impl TradingConfig {
+
+    // e.g. "SOL-PERP/SOL"
+    pub fn pair_name(&self) -> String {
+        format!("{}/{}", self.perp_market_name, self.token_name)
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if !(self.profit_threshold > 0.0) {
+            bail!("profit_threshold must be positive but was <{}>", self.profit_threshold);
+        }
+        if !(self.base_qty_ui > 0.0) {
+            bail!("base_qty_ui must be positive but was <{}>", self.base_qty_ui);
+        }
+        if !(self.perp_allowance_threshold_base_ui >= self.base_qty_ui) {
+            bail!("perp_allowance_threshold_base_ui <{}> must not be smaller than base_qty_ui <{}>",
+                self.perp_allowance_threshold_base_ui, self.base_qty_ui);
+        }
+        // prices are derived relative to the 6 decimals of USDC
+        if self.base_decimals < 6 {
+            bail!("base_decimals must be at least 6 but was <{}>", self.base_decimals);
+        }
+        Pubkey::from_str(&self.market).context("market is not a valid pubkey")?;
+        Pubkey::from_str(&self.mint_address_input).context("mint_address_input is not a valid pubkey")?;
+        Pubkey::from_str(&self.mint_address_output).context("mint_address_output is not a valid pubkey")?;
+        if self.mint_address_input == self.mint_address_output {
+            bail!("mint_address_input and mint_address_output must differ");
+        }
+        if let Some(band) = self.inventory_band_base_ui {
+            if !(band > 0.0) {
+                bail!("inventory_band_base_ui must be positive but was <{}>", band);
+            }
+        }
+        if !(self.inventory_max_cost_ui >= 0.0) {
+            bail!("inventory_max_cost_ui must not be negative but was <{}>", self.inventory_max_cost_ui);
+        }
+        if !(self.collateral_reserve_ui >= 0.0) {
+            bail!("collateral_reserve_ui must not be negative but was <{}>", self.collateral_reserve_ui);
+        }
+        if self.leg_retry_attempts == 0 {
+            bail!("leg_retry_attempts must be at least 1");
+        }
+        if let Some(size) = self.quote_ladder_ui.iter().find(|size| !(**size > 0.0)) {
+            bail!("quote_ladder_ui sizes must be positive but found <{}>", size);
+        }
+        if self.swap_quote_interval_ms == 0 {
+            bail!("swap_quote_interval_ms must be positive");
+        }
+        Ok(())
+    }
+
+    // check that the configured names, addresses and decimals match the mango group
+    pub fn validate_against_group(&self, context: &MangoGroupContext) -> anyhow::Result<()> {
+        let Some(perp_market_index) = context.perp_market_indexes_by_name.get(&self.perp_market_name) else {
+            bail!("perp market <{}> not found in mango group", self.perp_market_name);
+        };
+        let perp_market = context.perp_markets.get(perp_market_index).unwrap();
+        if perp_market.address != self.market_pubkey() {
+            bail!("perp market <{}> has address {} but config says {}",
+                self.perp_market_name, perp_market.address, self.market);
+        }
+        if perp_market.market.base_decimals != self.base_decimals {
+            bail!("perp market <{}> has {} base decimals but config says {}",
+                self.perp_market_name, perp_market.market.base_decimals, self.base_decimals);
+        }
+
+        let Some(token_index) = context.token_indexes_by_name.get(&self.token_name) else {
+            bail!("token <{}> not found in mango group", self.token_name);
+        };
+        let token = context.tokens.get(token_index).unwrap();
+        if token.mint_info.mint != self.mint_output() {
+            bail!("token <{}> has mint {} but config says {}",
+                self.token_name, token.mint_info.mint, self.mint_address_output);
+        }
+        if token.decimals != self.base_decimals {
+            bail!("token <{}> has {} decimals but config says {}",
+                self.token_name, token.decimals, self.base_decimals);
+        }
+
+        if !context.tokens.values().any(|token| token.mint_info.mint == self.mint_input()) {
+            bail!("quote mint {} not found in mango group", self.mint_address_input);
+        }
+
+        info!("trading config for '{}' vs '{}' matches mango group", self.perp_market_name, self.token_name);
+        Ok(())
+    }
+
+    // note: addresses are checked in validate()
+    pub fn market_pubkey(&self) -> Pubkey {
+        Pubkey::from_str(&self.market).unwrap()
+    }
+
+    pub fn mint_input(&self) -> Pubkey {
+        Pubkey::from_str(&self.mint_address_input).unwrap()
+    }
+
+    pub fn mint_output(&self) -> Pubkey {
+        Pubkey::from_str(&self.mint_address_output).unwrap()
+    }
+}
+
+// SOL-PERP/SOL with the defaults of all optional settings, for the tests of other modules
+#[cfg(test)]
+pub fn test_trading_config() -> TradingConfig {
+    toml::from_str(r#"
+        profit_threshold = 0.002
+        base_qty_ui = 1.0
+        perp_allowance_threshold_base_ui = 2.0
+        base_decimals = 9
+        market = "ESdnpnNLgTkBCZRuTJkZLi5wKEZ2z47SG3PJrhundSQ2"
+        perp_market_name = "SOL-PERP"
+        token_name = "SOL"
+        mint_address_input = "EPjFWdd5AufqSSqeM2qN1xzybapC8G4wEGGkZwyTDt1v"
+        mint_address_output = "So11111111111111111111111111111111111111112"
+    "#).unwrap()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn bot_config(pairs: Vec<TradingConfig>) -> BotConfig {
+        let mut bot_config: BotConfig = toml::from_str(r#"
+            sol_price_ui_estimate = 20.0
+            pair = []
+        "#).unwrap();
+        bot_config.pairs = pairs;
+        bot_config
+    }
+
+    fn validation_error(bot_config: &BotConfig) -> String {
+        format!("{:#}", bot_config.validate().unwrap_err())
+    }
+
+    #[test]
+    fn minimal_config_loads_with_defaults() {
+        let path = std::env::temp_dir().join(format!("trading-config-{}.toml", std::process::id()));
+        fs::write(&path, r#"
+            sol_price_ui_estimate = 20.0
+
+            [[pair]]
+            profit_threshold = 0.002
+            base_qty_ui = 1.0
+            perp_allowance_threshold_base_ui = 2.0
+            base_decimals = 9
+            market = "ESdnpnNLgTkBCZRuTJkZLi5wKEZ2z47SG3PJrhundSQ2"
+            perp_market_name = "SOL-PERP"
+            token_name = "SOL"
+            mint_address_input = "EPjFWdd5AufqSSqeM2qN1xzybapC8G4wEGGkZwyTDt1v"
+            mint_address_output = "So11111111111111111111111111111111111111112"
+        "#).unwrap();
+        let bot_config = BotConfig::load_from_file(path.to_str().unwrap());
+        fs::remove_file(&path).unwrap();
+        let bot_config = bot_config.unwrap();
+
+        assert_eq!(1, bot_config.max_concurrent_trades);
+        assert_eq!(OrderbookSource::MangoService, bot_config.orderbook_source);
+        assert_eq!(DEFAULT_JUPITER_V6_URL, bot_config.jupiter_v6_url);
+        let [pair] = &bot_config.pairs[..] else { panic!("expected one pair") };
+        assert_eq!("SOL-PERP/SOL", pair.pair_name());
+        assert_eq!(5, pair.swap_slippage_bps);
+        assert_eq!(3, pair.leg_retry_attempts);
+        assert_eq!(SwapMode::ExactOut, pair.swap_buy_mode);
+        assert_eq!(SwapMode::ExactIn, pair.swap_sell_mode);
+        assert_eq!(None, pair.inventory_band_base_ui);
+    }
+
+    #[test]
+    fn missing_config_file_is_an_error() {
+        let err = BotConfig::load_from_file("/nonexistent/trading-config.toml").unwrap_err();
+        assert!(format!("{:#}", err).contains("Can't read trading config file"));
+    }
+
+    #[test]
+    fn invalid_pair_values_are_rejected() {
+        assert!(bot_config(vec![test_trading_config()]).validate().is_ok());
+
+        let zero_size = bot_config(vec![TradingConfig { base_qty_ui: 0.0, ..test_trading_config() }]);
+        assert!(validation_error(&zero_size).contains("base_qty_ui must be positive"));
+
+        let negative_threshold = bot_config(vec![TradingConfig { profit_threshold: -0.001, ..test_trading_config() }]);
+        assert!(validation_error(&negative_threshold).contains("profit_threshold must be positive"));
+
+        let same_mints = bot_config(vec![TradingConfig {
+            mint_address_output: "EPjFWdd5AufqSSqeM2qN1xzybapC8G4wEGGkZwyTDt1v".to_string(),
+            ..test_trading_config()
+        }]);
+        assert!(validation_error(&same_mints).contains("must differ"));
+    }
+
+    #[test]
+    fn duplicate_and_missing_pairs_are_rejected() {
+        assert!(validation_error(&bot_config(vec![])).contains("at least one [[pair]]"));
+
+        let duplicate = bot_config(vec![test_trading_config(), test_trading_config()]);
+        assert!(validation_error(&duplicate).contains("perp market <SOL-PERP> is configured more than once"));
+    }
+}
@@ -1,99 +1,306 @@
-use std::sync::Arc;
-use serde::{Deserialize, Serialize};
-use serde_json::{from_str, json, Value};
-use tokio::net::TcpStream;
-use tokio_tungstenite::tungstenite::stream::MaybeTlsStream;
-use tokio_tungstenite::tungstenite::{connect, Message, WebSocket};
-use url::Url;
-use mango_v4_client::MangoClient;
-use crate::MangoClientRef;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use anyhow::{anyhow, bail};
+use futures::StreamExt;
+use log::{debug, info, warn};
+use mango_v4::state::MangoAccountValue;
+use solana_account_decoder::UiAccountEncoding;
+use solana_client::nonblocking::pubsub_client::PubsubClient;
+use solana_client::nonblocking::rpc_client::RpcClient;
+use solana_client::rpc_config::{RpcAccountInfoConfig, RpcSignatureSubscribeConfig};
+use solana_client::rpc_response::RpcSignatureResult;
+use solana_sdk::clock::MAX_PROCESSING_AGE;
+use solana_sdk::commitment_config::CommitmentConfig;
+use solana_sdk::pubkey::Pubkey;
+use solana_sdk::signature::Signature;
+use solana_sdk::transaction::TransactionError;
+use tokio::time::{interval, sleep, timeout};
+use crate::services::blockhash::{LatestBlockhash, start_blockhash_service};
+use crate::supervisor::Supervisor;
 
 // see https://github.com/blockworks-foundation/mangolana/blob/main/src/transactions.ts
 
-pub async fn await_transaction_signature_confirmation(mango_client: Arc<MangoClientRef>) {
+// how often the block height is checked for expiry (and statuses are polled in fallback mode)
+const POLL_INTERVAL: Duration = Duration::from_millis(1000);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+// the rpc node might not have reached the slot of a transaction confirmed through another node yet
+const ACCOUNT_AT_SLOT_ATTEMPTS: u32 = 10;
+// a blockhash newer than the polled one stays valid for up to this many more blocks
+const EXPIRY_MARGIN_BLOCKS: u64 = MAX_PROCESSING_AGE as u64;
 
-    // TODO wrap in solana_sdk::signature::Signature if possible
-    //
-    let txid = "3EtVaf1Go41W1dTkG8PtfrRDrrcBsiXzzWCmtmRr4Ce7YRDuPRJ4mXYhqK7zYsCrVAaCJqsPChCd8yUnPPki4WW1";
-    // TODO type it
-    let confirm_level = "confirmed"; // or processed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfirmationOutcome {
+    Confirmed,
+    Failed(TransactionError),
+    // blockhash expired before the transaction reached the requested commitment
+    Expired,
+}
 
-    let mut confirmation_levels = Vec::new();
-    // TODO implement
-    confirmation_levels.push("confirmed");
-    confirmation_levels.push("processed");
+pub struct TransactionConfirmer {
+    rpc_client: RpcClient,
+    ws_url: String,
+    blockhash: Arc<RwLock<LatestBlockhash>>,
+}
 
-    let foo = on_signature().await;
+impl TransactionConfirmer {
 
-    /*
-        export type BlockhashWithExpiryBlockHeight = Readonly<{
-        blockhash: Blockhash;
-        lastValidBlockHeight: number;
-        }>;
-     */
+    pub async fn new(rpc_url: String, ws_url: String, supervisor: &Supervisor) -> Self {
+        let blockhash = start_blockhash_service(rpc_url.clone(), supervisor).await;
+        TransactionConfirmer {
+            rpc_client: RpcClient::new_with_commitment(rpc_url, CommitmentConfig::confirmed()),
+            ws_url,
+            blockhash,
+        }
+    }
 
-    // mango_client.client.
+    // must be taken after the transaction was sent: the mango client signs with a blockhash fetched at processed
+    // commitment when sending, so only a blockhash fetched afterwards is at least as recent and expires no earlier
+    async fn last_valid_block_height(&self) -> u64 {
+        match self.rpc_client.get_latest_blockhash_with_commitment(CommitmentConfig::processed()).await {
+            Ok((_, last_valid_block_height)) => last_valid_block_height,
+            Err(err) => {
+                let polled = self.blockhash.read().unwrap().last_valid_block_height;
+                warn!("getLatestBlockhash failed, assuming expiry {} blocks after the polled blockhash: {}", EXPIRY_MARGIN_BLOCKS, err);
+                polled + EXPIRY_MARGIN_BLOCKS
+            }
+        }
+    }
 
-    todo!()
-}
+    // convenience for callers which treat anything but Confirmed as an error; call once the transaction was sent
+    pub async fn ensure_confirmed(&self, signature: Signature, commitment: CommitmentConfig) -> anyhow::Result<Signature> {
+        match self.await_transaction_signature_confirmation(&signature, commitment).await {
+            ConfirmationOutcome::Confirmed => Ok(signature),
+            ConfirmationOutcome::Failed(err) => Err(anyhow!("transaction {} failed: {}", signature, err)),
+            ConfirmationOutcome::Expired => Err(anyhow!("transaction {} expired before confirmation", signature)),
+        }
+    }
 
+    // slot the transaction was processed in
+    pub async fn confirmed_slot(&self, signature: &Signature) -> anyhow::Result<u64> {
+        let statuses = self.rpc_client.get_signature_statuses(&[*signature]).await?.value;
+        let Some(status) = statuses.into_iter().next().flatten() else {
+            bail!("no status for transaction {}", signature);
+        };
+        Ok(status.slot)
+    }
 
-#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
-#[serde(rename_all = "camelCase")]
-struct WsSubscription {
-    pub method_name: String,
-}
+    // mango account as of the given slot or later, bypassing the account cache of the mango client
+    pub async fn mango_account_at_slot(&self, address: &Pubkey, min_context_slot: u64) -> anyhow::Result<MangoAccountValue> {
+        let config = RpcAccountInfoConfig {
+            encoding: Some(UiAccountEncoding::Base64),
+            data_slice: None,
+            commitment: Some(CommitmentConfig::confirmed()),
+            min_context_slot: Some(min_context_slot),
+        };
+        let mut attempt = 1;
+        let account = loop {
+            match self.rpc_client.get_account_with_config(address, config.clone()).await {
+                Ok(response) => break response.value,
+                Err(err) if attempt < ACCOUNT_AT_SLOT_ATTEMPTS => {
+                    debug!("reading account {} at slot {} failed (attempt {}): {}", address, min_context_slot, attempt, err);
+                    attempt += 1;
+                    sleep(POLL_INTERVAL).await;
+                }
+                Err(err) => bail!("can't read account {} at slot {}: {}", address, min_context_slot, err),
+            }
+        };
+        let Some(account) = account else {
+            bail!("account {} not found", address);
+        };
+        // skip the anchor discriminator
+        let Some(data) = account.data.get(8..) else {
+            bail!("account {} is too small for a mango account", address);
+        };
+        MangoAccountValue::from_bytes(data).map_err(|err| anyhow!("can't decode mango account {}: {:?}", address, err))
+    }
+
+    // call once the transaction was sent
+    pub async fn await_transaction_signature_confirmation(&self, signature: &Signature, commitment: CommitmentConfig) -> ConfirmationOutcome {
+        let last_valid_block_height = self.last_valid_block_height().await;
+        debug!("awaiting {:?} confirmation of {} (last valid block height {})",
+            commitment.commitment, signature, last_valid_block_height);
 
-/*
- * Register a callback to be invoked upon signature updates
- *
- * @param signature Transaction signature string in base 58
- * @param callback Function to invoke on signature notifications
- * @param commitment Specify the commitment level signature must reach before notification
- * @return subscription id
- */
-pub async fn on_signature() -> anyhow::Result<()> {
+        let outcome = match timeout(CONNECT_TIMEOUT, PubsubClient::new(&self.ws_url)).await {
+            Ok(Ok(pubsub_client)) => {
+                let outcome = self.on_signature(&pubsub_client, signature, commitment, last_valid_block_height).await;
+                if let Err(err) = pubsub_client.shutdown().await {
+                    debug!("error closing signature subscription: {}", err);
+                }
+                outcome
+            }
+            Ok(Err(err)) => {
+                warn!("signatureSubscribe connection to {} failed, falling back to polling: {}", self.ws_url, err);
+                None
+            }
+            Err(_) => {
+                warn!("signatureSubscribe connection to {} timed out, falling back to polling", self.ws_url);
+                None
+            }
+        };
 
-    let (mut socket, response) =
-        connect(Url::parse("wss://api.mainnet-beta.solana.com").unwrap()).expect("Can't connect");
-    println!("Connected to the server: {:?}", response);
+        let outcome = match outcome {
+            Some(outcome) => outcome,
+            None => self.poll_signature_status(signature, commitment, last_valid_block_height).await,
+        };
 
-    if response.status() != 101 {
-        // TODO implement reconnects
-        panic!("Error connecting to the server: {:?}", response);
+        info!("confirmation outcome for {}: {:?}", signature, outcome);
+        outcome
     }
-    // see SubscriptionConfig in connection.ts
-    let sub = &WsSubscription {
-        method_name: "signatureSubscribe".to_string(),
-    };
 
-    socket.write_message(Message::text(json!(sub).to_string())).unwrap();
+    // returns None if the subscription broke and the caller should fall back to polling
+    async fn on_signature(&self, pubsub_client: &PubsubClient, signature: &Signature, commitment: CommitmentConfig,
+                          last_valid_block_height: u64) -> Option<ConfirmationOutcome> {
+        let config = RpcSignatureSubscribeConfig {
+            commitment: Some(commitment),
+            enable_received_notification: Some(false),
+        };
+        let (mut notifications, unsubscribe) = match pubsub_client.signature_subscribe(signature, Some(config)).await {
+            Ok(subscription) => subscription,
+            Err(err) => {
+                warn!("signatureSubscribe for {} failed, falling back to polling: {}", signature, err);
+                return None;
+            }
+        };
 
-    println!("subscribed");
+        // the transaction might have landed before the subscription was established
+        if let Ok(Some(outcome)) = self.signature_status(signature, commitment).await.map(|status| status_outcome(status, false)) {
+            unsubscribe().await;
+            return Some(outcome);
+        }
 
+        let mut expiry_check = interval(POLL_INTERVAL);
+        let outcome = loop {
+            tokio::select! {
+                notification = notifications.next() => {
+                    match notification {
+                        Some(response) => match response.value {
+                            RpcSignatureResult::ProcessedSignature(result) => break Some(processed_outcome(result.err)),
+                            RpcSignatureResult::ReceivedSignature(_) => continue,
+                        },
+                        None => {
+                            warn!("signature subscription for {} closed unexpectedly", signature);
+                            break None;
+                        }
+                    }
+                }
+                _ = expiry_check.tick() => {
+                    // a transaction which landed keeps waiting for its notification
+                    if self.is_expired(last_valid_block_height).await {
+                        if let Ok(Some(outcome)) = self.signature_status(signature, commitment).await.map(|status| status_outcome(status, true)) {
+                            break Some(outcome);
+                        }
+                    }
+                }
+            }
+        };
 
-    loop {
-        let msg = socket.read_message();
-        if let Message::Text(s) = msg.unwrap() {
-            let plain = from_str::<Value>(&s).expect("Can't parse to JSON");
-            println!("Received: {}", plain);
+        unsubscribe().await;
+        outcome
+    }
 
-            if !plain.get("event").is_some() {
-                continue;
+    // fallback using getSignatureStatuses
+    async fn poll_signature_status(&self, signature: &Signature, commitment: CommitmentConfig,
+                                   last_valid_block_height: u64) -> ConfirmationOutcome {
+        let mut poll_interval = interval(POLL_INTERVAL);
+        loop {
+            poll_interval.tick().await;
+            // checked before the status: a transaction which didn't land by then never will
+            let expired = self.is_expired(last_valid_block_height).await;
+            if let Ok(Some(outcome)) = self.signature_status(signature, commitment).await.map(|status| status_outcome(status, expired)) {
+                return outcome;
             }
+        }
+    }
 
-            // let fill_update_event = from_str::<FillUpdateEvent>(&s).expect("Can't parse to JSON");
-            //
-            // // TODO add assertions from https://github.com/blockworks-foundation/mango-v4/blob/max/mm/ts/client/scripts/mm/market-maker.ts#L185
-            //
-            // if fill_update_event.event.taker_client_order_id as u64 == search_order_client_id {
-            //     debug!("Recorded fill event for client order id: {}", search_order_client_id);
-            //     trace!("Fill Event: {:?}", fill_update_event);
-            //     return Ok(());
-            // }
+    // None if the rpc node doesn't know the transaction
+    async fn signature_status(&self, signature: &Signature, commitment: CommitmentConfig) -> anyhow::Result<Option<LandedStatus>> {
+        let statuses = match self.rpc_client.get_signature_statuses(&[*signature]).await {
+            Ok(statuses) => statuses.value,
+            Err(err) => {
+                warn!("getSignatureStatuses for {} failed: {}", signature, err);
+                return Err(err.into());
+            }
+        };
+        Ok(statuses.into_iter().next().flatten().map(|status| LandedStatus {
+            reached_commitment: status.satisfies_commitment(commitment),
+            err: status.err,
+        }))
+    }
+
+    async fn is_expired(&self, last_valid_block_height: u64) -> bool {
+        match self.rpc_client.get_block_height().await {
+            Ok(block_height) => is_past_last_valid_block_height(block_height, last_valid_block_height),
+            Err(err) => {
+                warn!("getBlockHeight failed: {}", err);
+                false
+            }
         }
-    } // -- loop
+    }
+}
+
+// status of a transaction the rpc node has seen
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct LandedStatus {
+    err: Option<TransactionError>,
+    reached_commitment: bool,
+}
+
+fn processed_outcome(err: Option<TransactionError>) -> ConfirmationOutcome {
+    match err {
+        None => ConfirmationOutcome::Confirmed,
+        Some(err) => ConfirmationOutcome::Failed(err),
+    }
+}
+
+// None while the outcome is open; a transaction which landed is never reported as expired
+fn status_outcome(status: Option<LandedStatus>, expired: bool) -> Option<ConfirmationOutcome> {
+    match status {
+        Some(LandedStatus { err: Some(err), .. }) => Some(ConfirmationOutcome::Failed(err)),
+        Some(LandedStatus { err: None, reached_commitment }) => reached_commitment.then_some(ConfirmationOutcome::Confirmed),
+        None if expired => Some(ConfirmationOutcome::Expired),
+        None => None,
+    }
+}
+
+fn is_past_last_valid_block_height(block_height: u64, last_valid_block_height: u64) -> bool {
+    block_height > last_valid_block_height
+}
+
+#[cfg(test)]
+mod test {
+    use solana_sdk::instruction::InstructionError;
+    use super::*;
+
+    fn landed(err: Option<TransactionError>, reached_commitment: bool) -> Option<LandedStatus> {
+        Some(LandedStatus { err, reached_commitment })
+    }
 
+    #[test]
+    fn landed_transaction_is_never_expired() {
+        assert_eq!(None, status_outcome(landed(None, false), true));
+        assert_eq!(Some(ConfirmationOutcome::Confirmed), status_outcome(landed(None, true), true));
+        assert_eq!(Some(ConfirmationOutcome::Failed(TransactionError::AccountInUse)),
+            status_outcome(landed(Some(TransactionError::AccountInUse), false), true));
+    }
+
+    #[test]
+    fn unknown_transaction_expires_with_its_blockhash() {
+        assert_eq!(None, status_outcome(None, false));
+        assert_eq!(Some(ConfirmationOutcome::Expired), status_outcome(None, true));
+        assert_eq!(None, status_outcome(landed(None, false), false));
+    }
 
-    panic!("endless loop");
+    #[test]
+    fn notification_maps_transaction_error() {
+        assert_eq!(ConfirmationOutcome::Confirmed, processed_outcome(None));
+        let err = TransactionError::InstructionError(0, InstructionError::Custom(6001));
+        assert_eq!(ConfirmationOutcome::Failed(err.clone()), processed_outcome(Some(err)));
+    }
+
+    #[test]
+    fn expires_after_last_valid_block_height() {
+        assert!(!is_past_last_valid_block_height(999, 1_000));
+        assert!(!is_past_last_valid_block_height(1_000, 1_000));
+        assert!(is_past_last_valid_block_height(1_001, 1_000));
+    }
 }
@@ -0,0 +1,104 @@
+use std::sync::Arc;
+use std::time::Duration;
+use log::{error, info, warn};
+use solana_sdk::commitment_config::CommitmentConfig;
+use crate::MangoClientRef;
+use crate::account_guard::AccountGuard;
+use crate::metrics::Metrics;
+use crate::services::feed_recorder::FeedRecorder;
+use crate::services::perp_orders::perp_cancel_all_orders;
+use crate::services::trading_config::BotConfig;
+use crate::services::transactions::TransactionConfirmer;
+use crate::trade_sequence::{TradeJournal, TradeState};
+
+// resolves with the name of the first SIGINT or SIGTERM received
+#[cfg(unix)]
+pub async fn shutdown_signal() -> &'static str {
+    use tokio::signal::unix::{signal, SignalKind};
+    let (Ok(mut interrupt), Ok(mut terminate)) = (signal(SignalKind::interrupt()), signal(SignalKind::terminate())) else {
+        warn!("Can't install shutdown signal handlers");
+        return std::future::pending().await;
+    };
+    tokio::select! {
+        _ = interrupt.recv() => "SIGINT",
+        _ = terminate.recv() => "SIGTERM",
+    }
+}
+
+#[cfg(not(unix))]
+pub async fn shutdown_signal() -> &'static str {
+    if tokio::signal::ctrl_c().await.is_err() {
+        warn!("Can't install shutdown signal handler");
+        return std::future::pending().await;
+    }
+    "Ctrl-C"
+}
+
+pub struct Shutdown {
+    pub mango_client: Arc<MangoClientRef>,
+    pub bot_config: Arc<BotConfig>,
+    pub account_guard: Arc<AccountGuard>,
+    pub trade_journal: Arc<TradeJournal>,
+    pub confirmer: Arc<TransactionConfirmer>,
+    pub recorder: Option<FeedRecorder>,
+    pub metrics: Arc<Metrics>,
+    pub dry_run: bool,
+}
+
+impl Shutdown {
+
+    // stop trading, let running trade sequences finish, cancel resting perp orders and flush what was recorded
+    pub async fn run(self) {
+        // another signal while shutting down skips the rest, e.g. when the RPC node hangs
+        tokio::spawn(async {
+            let signal = shutdown_signal().await;
+            error!("{} received while shutting down, exiting immediately - check the positions of the account", signal);
+            std::process::exit(1);
+        });
+        self.metrics.set_gauge("shutting_down", &[], 1.0);
+
+        let max_wait = Duration::from_secs(self.bot_config.shutdown_timeout_secs);
+        info!("Refusing new trades, waiting up to {:?} for running trade sequences ...", max_wait);
+        if !self.account_guard.shutdown(max_wait).await {
+            warn!("Trade sequences still running after {:?}, shutting down anyway", max_wait);
+        }
+
+        for sequence in self.trade_journal.snapshot() {
+            if sequence.state() == TradeState::Closed {
+                let realized = sequence.realized_cash_flow()
+                    .map(|realized| format!("{:.6}", realized))
+                    .unwrap_or_else(|| "unknown".to_string());
+                info!("trade sequence {} ({} {}) closed with outcome {:?}, realized cash flow {}",
+                    sequence.id, sequence.pair_name, sequence.direction, sequence.outcome(), realized);
+            } else {
+                error!("trade sequence {} ({} {}) left in state {:?} - check the positions of the account",
+                    sequence.id, sequence.pair_name, sequence.direction, sequence.state());
+            }
+        }
+
+        if !self.dry_run {
+            self.cancel_perp_orders().await;
+        }
+
+        if let Some(recorder) = &self.recorder {
+            recorder.flush().await;
+        }
+        info!("Final metrics:\n{}", self.metrics.render());
+        info!("Shutdown complete");
+    }
+
+    async fn cancel_perp_orders(&self) {
+        for trading_config in &self.bot_config.pairs {
+            let cancelled = match perp_cancel_all_orders(&self.mango_client, trading_config).await {
+                Ok(Some(signature)) => self.confirmer.ensure_confirmed(signature, CommitmentConfig::confirmed()).await
+                    .map(Some),
+                other => other,
+            };
+            match cancelled {
+                Ok(Some(signature)) => info!("{}: cancelled resting perp orders ({})", trading_config.pair_name(), signature),
+                Ok(None) => info!("{}: no resting perp orders", trading_config.pair_name()),
+                Err(err) => error!("{}: failed to cancel resting perp orders: {:#}", trading_config.pair_name(), err),
+            }
+        }
+    }
+}
@@ -0,0 +1,144 @@
+use std::future::Future;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use log::{error, info, warn};
+use tokio::sync::Mutex;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::time::sleep;
+use crate::metrics::Metrics;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum RestartPolicy {
+    // wait min_backoff before the first restart, doubling up to max_backoff;
+    // a task which ran for at least max_backoff starts over with min_backoff
+    Restart { min_backoff: Duration, max_backoff: Duration },
+    // shut the bot down once the task ends
+    Escalate,
+}
+
+// feeds and pollers which only read market data
+pub const RESTART_WITH_BACKOFF: RestartPolicy = RestartPolicy::Restart {
+    min_backoff: Duration::from_secs(1),
+    max_backoff: Duration::from_secs(60),
+};
+
+// runs named background tasks, restarts them or escalates when they return or panic;
+// liveness is exported as task_up{task} and task_restarts_total{task}
+pub struct Supervisor {
+    metrics: Arc<Metrics>,
+    escalation_sender: UnboundedSender<String>,
+    escalation_receiver: Mutex<UnboundedReceiver<String>>,
+}
+
+impl Supervisor {
+
+    pub fn new(metrics: Arc<Metrics>) -> Self {
+        let (escalation_sender, escalation_receiver) = unbounded_channel();
+        Supervisor {
+            metrics,
+            escalation_sender,
+            escalation_receiver: Mutex::new(escalation_receiver),
+        }
+    }
+
+    // the factory creates a fresh instance of the task for every (re)start
+    pub fn supervise<F, Fut>(&self, name: &str, policy: RestartPolicy, mut factory: F)
+        where F: FnMut() -> Fut + Send + 'static,
+              Fut: Future<Output = ()> + Send + 'static {
+        let name = name.to_string();
+        let metrics = self.metrics.clone();
+        let escalation_sender = self.escalation_sender.clone();
+        info!("Starting supervised task <{}> ({:?})", name, policy);
+        tokio::spawn(async move {
+            let labels = [("task", name.as_str())];
+            let mut restarts = 0;
+            let mut backoff = Duration::ZERO;
+            loop {
+                let started_at = Instant::now();
+                metrics.set_gauge("task_up", &labels, 1.0);
+                let result = tokio::spawn(factory()).await;
+                metrics.set_gauge("task_up", &labels, 0.0);
+                let outcome = match result {
+                    Ok(()) => "exited".to_string(),
+                    Err(err) => format!("failed: {}", err),
+                };
+
+                let RestartPolicy::Restart { min_backoff, max_backoff } = policy else {
+                    error!("Task <{}> {}, shutting down", name, outcome);
+                    // the receiver lives as long as the supervisor
+                    let _ = escalation_sender.send(format!("task <{}> {}", name, outcome));
+                    return;
+                };
+                backoff = if started_at.elapsed() >= max_backoff || backoff.is_zero() {
+                    min_backoff
+                } else {
+                    (backoff * 2).min(max_backoff)
+                };
+                restarts += 1;
+                warn!("Task <{}> {}, restart #{} in {:?}", name, outcome, restarts, backoff);
+                metrics.inc_counter("task_restarts_total", &labels);
+                sleep(backoff).await;
+            }
+        });
+    }
+
+    // for tasks which can't be restarted, e.g. because they own a receiver
+    pub fn supervise_once<Fut>(&self, name: &str, task: Fut)
+        where Fut: Future<Output = ()> + Send + 'static {
+        let mut task = Some(task);
+        self.supervise(name, RestartPolicy::Escalate,
+            move || task.take().expect("escalating tasks are started once"));
+    }
+
+    // waits for the first task which escalated
+    pub async fn escalated(&self) -> String {
+        self.escalation_receiver.lock().await.recv().await
+            .unwrap_or_else(|| "supervisor closed".to_string())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::future::pending;
+    use tokio::time::timeout;
+    use super::*;
+
+    #[tokio::test]
+    async fn failing_task_is_restarted() {
+        let metrics = Arc::new(Metrics::default());
+        let supervisor = Supervisor::new(metrics.clone());
+        let starts = Arc::new(AtomicUsize::new(0));
+        let policy = RestartPolicy::Restart { min_backoff: Duration::from_millis(1), max_backoff: Duration::from_millis(10) };
+        supervisor.supervise("flaky feed", policy, {
+            let starts = starts.clone();
+            move || {
+                let starts = starts.clone();
+                async move {
+                    // returns twice, then stays up
+                    if starts.fetch_add(1, Ordering::SeqCst) < 2 {
+                        return;
+                    }
+                    pending::<()>().await;
+                }
+            }
+        });
+
+        timeout(Duration::from_secs(5), async {
+            while starts.load(Ordering::SeqCst) < 3 {
+                sleep(Duration::from_millis(1)).await;
+            }
+        }).await.unwrap();
+        assert!(metrics.render().contains("task_restarts_total{task=\"flaky feed\"} 2"));
+        assert!(metrics.render().contains("task_up{task=\"flaky feed\"} 1"));
+    }
+
+    #[tokio::test]
+    async fn ended_task_escalates() {
+        let supervisor = Supervisor::new(Arc::new(Metrics::default()));
+        supervisor.supervise_once("trading loop", async {});
+
+        let escalation = timeout(Duration::from_secs(5), supervisor.escalated()).await.unwrap();
+        assert_eq!("task <trading loop> exited", escalation);
+    }
+}
@@ -0,0 +1,556 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use async_trait::async_trait;
+use log::{error, info, warn};
+use solana_sdk::signature::Signature;
+use tokio::time::sleep;
+use crate::services::fills_stream::PerpFill;
+
+// number of finished trade sequences kept for inspection
+const JOURNAL_CAPACITY: usize = 100;
+// orderbook quantities are rounded to lots
+pub const COMPLETE_FILL_RATIO: f64 = 0.999;
+
+//
+// Pending -> LegAFilled -> LegBFilled -> Closed
+//                      \-> Unwinding -> Closed (leg B failed or stayed partial)
+//                      \-> Closed (leg B confirmed but unverified)
+// Pending -> Closed (leg A failed, nothing to hedge)
+//
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TradeState {
+    Pending,
+    LegAFilled,
+    LegBFilled,
+    Unwinding,
+    Closed,
+}
+
+impl TradeState {
+    fn can_transition_to(&self, next: TradeState) -> bool {
+        use TradeState::*;
+        matches!((self, next),
+            (Pending, LegAFilled) | (Pending, Closed)
+            | (LegAFilled, LegBFilled) | (LegAFilled, Unwinding) | (LegAFilled, Closed)
+            | (LegBFilled, Closed)
+            | (Unwinding, Closed))
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TradeOutcome {
+    // both legs filled
+    Hedged,
+    // leg A failed, no position was opened
+    Aborted,
+    // leg B failed or stayed partially filled, the unhedged part of leg A was reverted
+    Unwound,
+    // leg B and the unwind failed, a leg was only partially filled or could not be verified - position remains open
+    Unhedged,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Direction {
+    // buy on jupiter, short on perp
+    Swap2Perp,
+    // long on perp, sell on jupiter
+    Perp2Swap,
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Direction::Swap2Perp => write!(f, "swap->perp"),
+            Direction::Perp2Swap => write!(f, "perp->swap"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Transition {
+    pub from: TradeState,
+    pub to: TradeState,
+    pub elapsed: Duration,
+    pub note: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct TradeSequence {
+    pub id: u64,
+    pub pair_name: String,
+    pub direction: Direction,
+    started_at: Instant,
+    state: TradeState,
+    transitions: Vec<Transition>,
+    // executed legs including retries and unwind
+    legs: Vec<LegReport>,
+    outcome: Option<TradeOutcome>,
+}
+
+impl TradeSequence {
+
+    pub fn new(id: u64, pair_name: String, direction: Direction) -> Self {
+        TradeSequence {
+            id,
+            pair_name,
+            direction,
+            started_at: Instant::now(),
+            state: TradeState::Pending,
+            transitions: vec![],
+            legs: vec![],
+            outcome: None,
+        }
+    }
+
+    pub fn state(&self) -> TradeState {
+        self.state
+    }
+
+    pub fn transitions(&self) -> &[Transition] {
+        &self.transitions
+    }
+
+    pub fn legs(&self) -> &[LegReport] {
+        &self.legs
+    }
+
+    pub fn outcome(&self) -> Option<TradeOutcome> {
+        self.outcome
+    }
+
+    // sum of quote cash flows of the perp legs with known fills
+    pub fn perp_cash_flow(&self) -> f64 {
+        self.legs.iter()
+            .filter_map(|leg| leg.perp_fill.as_ref())
+            .map(|fill| fill.quote_cash_flow())
+            .sum()
+    }
+
+    // sum of USDC flows of the swap legs
+    pub fn swap_cash_flow(&self) -> f64 {
+        self.legs.iter()
+            .filter_map(|leg| leg.swap_quote_delta)
+            .sum()
+    }
+
+    // quote cash flow of all executed legs; realized PnL once the sequence is hedged or unwound -
+    // None if a leg traded without a known cash flow
+    pub fn realized_cash_flow(&self) -> Option<f64> {
+        self.legs.iter().all(LegReport::cash_flow_known)
+            .then(|| self.perp_cash_flow() + self.swap_cash_flow())
+    }
+
+    fn transition(&mut self, next: TradeState, note: String) {
+        assert!(self.state.can_transition_to(next),
+            "invalid trade sequence transition {:?} -> {:?}", self.state, next);
+        info!("trade sequence {} ({} {}): {:?} -> {:?}: {}",
+            self.id, self.direction, self.pair_name, self.state, next, note);
+        self.transitions.push(Transition {
+            from: self.state,
+            to: next,
+            elapsed: self.started_at.elapsed(),
+            note,
+        });
+        self.state = next;
+    }
+
+    fn close(&mut self, outcome: TradeOutcome, note: String) {
+        self.transition(TradeState::Closed, note);
+        self.outcome = Some(outcome);
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LegReport {
+    pub signature: Signature,
+    // base quantity (ui) the leg was sent for
+    pub requested_base_ui: f64,
+    // base quantity (ui) the leg actually traded
+    pub executed_base_ui: f64,
+    // only for perp legs
+    pub perp_fill: Option<PerpFill>,
+    // only for swap legs: change of the mango account token position (ui)
+    pub swap_base_delta: Option<f64>,
+    // only for swap legs: change of the mango account USDC position (ui)
+    pub swap_quote_delta: Option<f64>,
+}
+
+impl LegReport {
+    pub fn is_complete(&self) -> bool {
+        self.executed_base_ui >= self.requested_base_ui * COMPLETE_FILL_RATIO
+    }
+
+    // false e.g. for a perp order whose fill the fills feed missed and whose quantity was taken from the position
+    pub fn cash_flow_known(&self) -> bool {
+        if self.swap_quote_delta.is_some() {
+            return true;
+        }
+        let filled = self.perp_fill.as_ref().map(|fill| fill.quantity()).unwrap_or(0.0);
+        filled >= self.executed_base_ui * COMPLETE_FILL_RATIO
+    }
+}
+
+impl fmt::Display for LegReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "signature {}, executed {:.9} of {:.9}", self.signature, self.executed_base_ui, self.requested_base_ui)?;
+        if let Some(fill) = &self.perp_fill {
+            write!(f, ", perp fill {} @ {:.4} (fees {:.6}, cash flow {:.6})",
+                fill.quantity(), fill.avg_price(), fill.fees(), fill.quote_cash_flow())?;
+        }
+        if let Some(delta) = self.swap_base_delta {
+            write!(f, ", token position {:+.9}", delta)?;
+        }
+        if let Some(delta) = self.swap_quote_delta {
+            write!(f, ", USDC position {:+.6}", delta)?;
+        }
+        Ok(())
+    }
+}
+
+// only a leg which executed nothing may be sent again
+#[derive(Debug)]
+pub enum LegError {
+    // rejected, failed or expired before it traded
+    NotExecuted(anyhow::Error),
+    // the transaction confirmed but what it traded is not known
+    Unverified { report: LegReport, reason: anyhow::Error },
+}
+
+impl fmt::Display for LegError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LegError::NotExecuted(err) => write!(f, "{:#}", err),
+            LegError::Unverified { report, reason } => write!(f, "confirmed with {} but unverified: {:#}", report, reason),
+        }
+    }
+}
+
+impl std::error::Error for LegError {}
+
+pub type LegResult = Result<LegReport, LegError>;
+
+// the two legs of a hedged trade; leg B hedges leg A, unwind_a reverts leg A -
+// both are sized by the quantity leg A actually traded
+#[async_trait]
+pub trait TradeLegs: Send + Sync {
+    async fn leg_a(&self) -> LegResult;
+    async fn leg_b(&self, base_quantity_ui: f64) -> LegResult;
+    async fn unwind_a(&self, base_quantity_ui: f64) -> LegResult;
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct RetryBudget {
+    // attempts including the first one
+    pub max_attempts: u32,
+    pub delay: Duration,
+}
+
+// recently executed trade sequences including the one in flight
+#[derive(Default)]
+pub struct TradeJournal {
+    sequences: Mutex<VecDeque<TradeSequence>>,
+}
+
+impl TradeJournal {
+
+    pub fn snapshot(&self) -> Vec<TradeSequence> {
+        self.sequences.lock().unwrap().iter().cloned().collect()
+    }
+
+    fn update(&self, sequence: &TradeSequence) {
+        let mut sequences = self.sequences.lock().unwrap();
+        if let Some(existing) = sequences.iter_mut().find(|seq| seq.id == sequence.id) {
+            *existing = sequence.clone();
+            return;
+        }
+        if sequences.len() >= JOURNAL_CAPACITY {
+            sequences.pop_front();
+        }
+        sequences.push_back(sequence.clone());
+    }
+}
+
+pub async fn execute_trade_sequence(mut sequence: TradeSequence, legs: &dyn TradeLegs,
+                                    retry_budget: RetryBudget, journal: &TradeJournal) -> TradeSequence {
+    journal.update(&sequence);
+
+    let hedge_quantity = match legs.leg_a().await {
+        Ok(report) if report.executed_base_ui > 0.0 => {
+            sequence.transition(TradeState::LegAFilled, format!("leg A {}", report));
+            let executed_base_ui = report.executed_base_ui;
+            sequence.legs.push(report);
+            executed_base_ui
+        }
+        Ok(report) => {
+            sequence.close(TradeOutcome::Aborted, format!("leg A traded nothing: {}", report));
+            sequence.legs.push(report);
+            journal.update(&sequence);
+            return sequence;
+        }
+        Err(LegError::NotExecuted(err)) => {
+            sequence.close(TradeOutcome::Aborted, format!("leg A failed: {:#}", err));
+            journal.update(&sequence);
+            return sequence;
+        }
+        Err(LegError::Unverified { report, reason }) => {
+            error!("leg A of trade sequence {} confirmed but unverified - check the positions of the account!", sequence.id);
+            sequence.close(TradeOutcome::Unhedged, format!("leg A confirmed with {} but unverified: {:#}", report, reason));
+            sequence.legs.push(report);
+            journal.update(&sequence);
+            return sequence;
+        }
+    };
+    journal.update(&sequence);
+
+    // the remainder of a partial fill is sent again within the same retry budget
+    let mut attempts = 0;
+    let mut hedged = 0.0;
+    let unwind_reason = loop {
+        let remaining = hedge_quantity - hedged;
+        match with_retries(retry_budget, &mut attempts, "leg B", || legs.leg_b(remaining)).await {
+            Ok(report) => {
+                hedged += report.executed_base_ui;
+                let note = format!("leg B {}", report);
+                sequence.legs.push(report);
+                if hedged >= hedge_quantity * COMPLETE_FILL_RATIO {
+                    sequence.transition(TradeState::LegBFilled, note);
+                    sequence.close(TradeOutcome::Hedged, "trade sequence completed".to_string());
+                    journal.update(&sequence);
+                    return sequence;
+                }
+                if attempts >= retry_budget.max_attempts {
+                    break format!("leg B hedged {:.9} of {:.9} within {} attempts", hedged, hedge_quantity, attempts);
+                }
+                warn!("leg B of trade sequence {} hedged {:.9} of {:.9} (attempt {}/{}), sending the remainder in {:?}",
+                    sequence.id, hedged, hedge_quantity, attempts, retry_budget.max_attempts, retry_budget.delay);
+                journal.update(&sequence);
+                sleep(retry_budget.delay).await;
+            }
+            Err(LegError::Unverified { report, reason }) => {
+                error!("leg B of trade sequence {} confirmed but unverified - check the positions of the account!", sequence.id);
+                sequence.close(TradeOutcome::Unhedged, format!("leg B confirmed with {} but unverified: {:#}", report, reason));
+                sequence.legs.push(report);
+                journal.update(&sequence);
+                return sequence;
+            }
+            Err(LegError::NotExecuted(err)) => break format!("leg B failed: {:#}", err),
+        }
+    };
+
+    let unhedged = hedge_quantity - hedged;
+    sequence.transition(TradeState::Unwinding, format!("{}, unwinding {:.9} of leg A", unwind_reason, unhedged));
+    journal.update(&sequence);
+    match with_retries(retry_budget, &mut 0, "unwind", || legs.unwind_a(unhedged)).await {
+        Ok(report) if report.is_complete() => {
+            sequence.close(TradeOutcome::Unwound, format!("leg A unwound with {}", report));
+            sequence.legs.push(report);
+        }
+        Ok(report) => {
+            error!("unwind of trade sequence {} only partially filled - position remains open!", sequence.id);
+            sequence.close(TradeOutcome::Unhedged, format!("leg A partially unwound with {}", report));
+            sequence.legs.push(report);
+        }
+        Err(LegError::Unverified { report, reason }) => {
+            error!("unwind of trade sequence {} confirmed but unverified - check the positions of the account!", sequence.id);
+            sequence.close(TradeOutcome::Unhedged, format!("unwind confirmed with {} but unverified: {:#}", report, reason));
+            sequence.legs.push(report);
+        }
+        Err(LegError::NotExecuted(err)) => {
+            error!("failed to unwind leg A of trade sequence {} - position remains open!", sequence.id);
+            sequence.close(TradeOutcome::Unhedged, format!("unwind failed: {:#}", err));
+        }
+    }
+    journal.update(&sequence);
+
+    sequence
+}
+
+// a leg is only sent again if the previous attempt traded nothing; attempts counts the calls made with this budget
+async fn with_retries<F, Fut>(retry_budget: RetryBudget, attempts: &mut u32, name: &str, call: F) -> LegResult
+    where F: Fn() -> Fut, Fut: Future<Output = LegResult> {
+    loop {
+        *attempts += 1;
+        match call().await {
+            Err(LegError::NotExecuted(err)) if *attempts < retry_budget.max_attempts => {
+                warn!("{} failed (attempt {}/{}), retrying in {:?}: {:#}",
+                    name, attempts, retry_budget.max_attempts, retry_budget.delay, err);
+                sleep(retry_budget.delay).await;
+            }
+            result => return result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Mutex;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::time::Duration;
+    use anyhow::anyhow;
+    use async_trait::async_trait;
+    use solana_sdk::signature::Signature;
+    use crate::trade_sequence::*;
+
+    struct FakeLegs {
+        leg_a_ok: bool,
+        leg_b_failures: u32,
+        // after the failures, leg B fills half of the requested quantity this many times
+        leg_b_partial_fills: u32,
+        unwind_ok: bool,
+        leg_b_calls: AtomicU32,
+        // of the requested 1.0
+        leg_a_executed: f64,
+        leg_b_unverified: bool,
+        leg_b_quantities: Mutex<Vec<f64>>,
+        unwind_quantity: Mutex<Option<f64>>,
+    }
+
+    fn legs() -> FakeLegs {
+        FakeLegs {
+            leg_a_ok: true,
+            leg_b_failures: 0,
+            leg_b_partial_fills: 0,
+            unwind_ok: true,
+            leg_b_calls: AtomicU32::new(0),
+            leg_a_executed: 1.0,
+            leg_b_unverified: false,
+            leg_b_quantities: Mutex::new(vec![]),
+            unwind_quantity: Mutex::new(None),
+        }
+    }
+
+    impl FakeLegs {
+        fn leg_b_calls(&self) -> u32 {
+            self.leg_b_calls.load(Ordering::SeqCst)
+        }
+    }
+
+    #[async_trait]
+    impl TradeLegs for FakeLegs {
+        async fn leg_a(&self) -> LegResult {
+            if self.leg_a_ok { Ok(report(1.0, self.leg_a_executed)) } else { Err(LegError::NotExecuted(anyhow!("leg A rejected"))) }
+        }
+
+        async fn leg_b(&self, base_quantity_ui: f64) -> LegResult {
+            self.leg_b_quantities.lock().unwrap().push(base_quantity_ui);
+            let call = self.leg_b_calls.fetch_add(1, Ordering::SeqCst);
+            if self.leg_b_unverified {
+                return Err(LegError::Unverified { report: report(base_quantity_ui, 0.0), reason: anyhow!("fill not seen") });
+            }
+            if call < self.leg_b_failures {
+                return Err(LegError::NotExecuted(anyhow!("leg B rejected")));
+            }
+            if call < self.leg_b_failures + self.leg_b_partial_fills {
+                return Ok(report(base_quantity_ui, base_quantity_ui / 2.0));
+            }
+            Ok(report(base_quantity_ui, base_quantity_ui))
+        }
+
+        async fn unwind_a(&self, base_quantity_ui: f64) -> LegResult {
+            *self.unwind_quantity.lock().unwrap() = Some(base_quantity_ui);
+            if self.unwind_ok { Ok(report(base_quantity_ui, base_quantity_ui)) } else { Err(LegError::NotExecuted(anyhow!("unwind rejected"))) }
+        }
+    }
+
+    fn report(requested_base_ui: f64, executed_base_ui: f64) -> LegReport {
+        LegReport {
+            signature: Signature::default(),
+            requested_base_ui,
+            executed_base_ui,
+            perp_fill: None,
+            swap_base_delta: None,
+            swap_quote_delta: None,
+        }
+    }
+
+    const BUDGET: RetryBudget = RetryBudget { max_attempts: 3, delay: Duration::from_millis(1) };
+
+    async fn run(legs: &FakeLegs) -> TradeSequence {
+        let journal = TradeJournal::default();
+        let sequence = TradeSequence::new(1, "SOL-PERP/SOL".to_string(), Direction::Swap2Perp);
+        let sequence = execute_trade_sequence(sequence, legs, BUDGET, &journal).await;
+        assert_eq!(journal.snapshot().len(), 1);
+        assert_eq!(journal.snapshot()[0].state(), TradeState::Closed);
+        sequence
+    }
+
+    fn states(sequence: &TradeSequence) -> Vec<TradeState> {
+        sequence.transitions().iter().map(|t| t.to).collect()
+    }
+
+    #[tokio::test]
+    async fn hedged_after_leg_b_retry() {
+        let legs = FakeLegs { leg_b_failures: 2, ..legs() };
+        let sequence = run(&legs).await;
+        assert_eq!(sequence.outcome(), Some(TradeOutcome::Hedged));
+        assert_eq!(states(&sequence), vec![TradeState::LegAFilled, TradeState::LegBFilled, TradeState::Closed]);
+        assert_eq!(legs.leg_b_calls(), 3);
+    }
+
+    #[tokio::test]
+    async fn unwind_when_retry_budget_exhausted() {
+        let legs = FakeLegs { leg_b_failures: 3, ..legs() };
+        let sequence = run(&legs).await;
+        assert_eq!(sequence.outcome(), Some(TradeOutcome::Unwound));
+        assert_eq!(states(&sequence), vec![TradeState::LegAFilled, TradeState::Unwinding, TradeState::Closed]);
+        assert_eq!(legs.leg_b_calls(), BUDGET.max_attempts);
+        assert_eq!(*legs.unwind_quantity.lock().unwrap(), Some(1.0));
+    }
+
+    #[tokio::test]
+    async fn unhedged_when_unwind_fails() {
+        let legs = FakeLegs { leg_b_failures: 10, unwind_ok: false, ..legs() };
+        let sequence = run(&legs).await;
+        assert_eq!(sequence.outcome(), Some(TradeOutcome::Unhedged));
+        assert_eq!(legs.leg_b_calls(), BUDGET.max_attempts);
+    }
+
+    #[tokio::test]
+    async fn abort_when_leg_a_fails() {
+        let legs = FakeLegs { leg_a_ok: false, ..legs() };
+        let sequence = run(&legs).await;
+        assert_eq!(sequence.outcome(), Some(TradeOutcome::Aborted));
+        assert_eq!(states(&sequence), vec![TradeState::Closed]);
+        assert_eq!(legs.leg_b_calls(), 0);
+    }
+
+    #[tokio::test]
+    async fn unverified_leg_b_is_not_sent_again() {
+        let legs = FakeLegs { leg_b_unverified: true, ..legs() };
+        let sequence = run(&legs).await;
+        assert_eq!(sequence.outcome(), Some(TradeOutcome::Unhedged));
+        assert_eq!(states(&sequence), vec![TradeState::LegAFilled, TradeState::Closed]);
+        assert_eq!(legs.leg_b_calls(), 1);
+    }
+
+    #[tokio::test]
+    async fn leg_b_hedges_the_executed_quantity() {
+        let legs = FakeLegs { leg_a_executed: 0.6, ..legs() };
+        let sequence = run(&legs).await;
+        assert_eq!(sequence.outcome(), Some(TradeOutcome::Hedged));
+        assert_eq!(*legs.leg_b_quantities.lock().unwrap(), vec![0.6]);
+    }
+
+    #[tokio::test]
+    async fn partly_filled_leg_b_sends_the_remainder() {
+        let legs = FakeLegs { leg_b_failures: 1, leg_b_partial_fills: 1, ..legs() };
+        let sequence = run(&legs).await;
+        assert_eq!(sequence.outcome(), Some(TradeOutcome::Hedged));
+        assert_eq!(states(&sequence), vec![TradeState::LegAFilled, TradeState::LegBFilled, TradeState::Closed]);
+        assert_eq!(*legs.leg_b_quantities.lock().unwrap(), vec![1.0, 1.0, 0.5]);
+        assert_eq!(sequence.legs().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn unwind_the_unhedged_part_when_leg_b_stays_partial() {
+        let legs = FakeLegs { leg_b_partial_fills: 10, ..legs() };
+        let sequence = run(&legs).await;
+        assert_eq!(sequence.outcome(), Some(TradeOutcome::Unwound));
+        assert_eq!(states(&sequence), vec![TradeState::LegAFilled, TradeState::Unwinding, TradeState::Closed]);
+        assert_eq!(*legs.leg_b_quantities.lock().unwrap(), vec![1.0, 0.5, 0.25]);
+        assert_eq!(*legs.unwind_quantity.lock().unwrap(), Some(0.125));
+    }
+}